@@ -0,0 +1,41 @@
+-- Repositories, try jobs and the merge queue
+CREATE TABLE IF NOT EXISTS repositories (
+    id BIGINT PRIMARY KEY,
+    name TEXT NOT NULL,
+    full_name TEXT NOT NULL UNIQUE,
+    owner TEXT NOT NULL,
+    default_branch TEXT NOT NULL DEFAULT 'main',
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    updated_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS try_merge_jobs (
+    id UUID PRIMARY KEY,
+    repository_id BIGINT NOT NULL,
+    pr_number INTEGER NOT NULL,
+    branch_name TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    updated_at TIMESTAMPTZ DEFAULT NOW(),
+    error_message TEXT,
+    CONSTRAINT fk_repository FOREIGN KEY (repository_id) REFERENCES repositories(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_try_merge_jobs_repo_pr
+ON try_merge_jobs(repository_id, pr_number);
+
+CREATE TABLE IF NOT EXISTS merge_queue (
+    id UUID PRIMARY KEY,
+    repository_id BIGINT NOT NULL,
+    pr_number INTEGER NOT NULL,
+    approved_by TEXT NOT NULL,
+    priority INTEGER NOT NULL DEFAULT 0,
+    status TEXT NOT NULL DEFAULT 'queued',
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    updated_at TIMESTAMPTZ DEFAULT NOW(),
+    error_message TEXT,
+    CONSTRAINT fk_merge_queue_repository FOREIGN KEY (repository_id) REFERENCES repositories(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_merge_queue_repo_status
+ON merge_queue(repository_id, status);
@@ -0,0 +1,5 @@
+-- Rollup batches, reactions and check runs of merge queue entries
+ALTER TABLE merge_queue ADD COLUMN IF NOT EXISTS batch_id UUID;
+ALTER TABLE merge_queue ADD COLUMN IF NOT EXISTS rollup TEXT NOT NULL DEFAULT 'maybe';
+ALTER TABLE merge_queue ADD COLUMN IF NOT EXISTS comment_id BIGINT;
+ALTER TABLE merge_queue ADD COLUMN IF NOT EXISTS check_run_id BIGINT;
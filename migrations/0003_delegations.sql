@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS delegations (
+    repository_id BIGINT NOT NULL,
+    pr_number INTEGER NOT NULL,
+    delegate TEXT NOT NULL,
+    delegated_by TEXT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    PRIMARY KEY (repository_id, pr_number, delegate),
+    CONSTRAINT fk_delegations_repository FOREIGN KEY (repository_id) REFERENCES repositories(id)
+);
@@ -0,0 +1,9 @@
+-- Leases let any worker pick up pending jobs and jobs whose worker stopped heartbeating
+ALTER TABLE try_merge_jobs ADD COLUMN IF NOT EXISTS lease_owner TEXT;
+ALTER TABLE try_merge_jobs ADD COLUMN IF NOT EXISTS lease_expires_at TIMESTAMPTZ;
+ALTER TABLE try_merge_jobs ADD COLUMN IF NOT EXISTS attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE try_merge_jobs ADD COLUMN IF NOT EXISTS comment_id BIGINT;
+ALTER TABLE try_merge_jobs ADD COLUMN IF NOT EXISTS check_run_id BIGINT;
+
+CREATE INDEX IF NOT EXISTS idx_try_merge_jobs_status
+ON try_merge_jobs(status, created_at);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS repository_locks (
+    repository_id BIGINT NOT NULL,
+    resource TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    fencing_token BIGINT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (repository_id, resource)
+);
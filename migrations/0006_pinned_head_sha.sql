@@ -0,0 +1,3 @@
+-- The PR head commit approvals and try builds are pinned to
+ALTER TABLE try_merge_jobs ADD COLUMN IF NOT EXISTS head_sha TEXT;
+ALTER TABLE merge_queue ADD COLUMN IF NOT EXISTS head_sha TEXT;
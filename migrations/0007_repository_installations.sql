@@ -0,0 +1,6 @@
+-- Repositories are recorded from webhooks and soft-deleted when the app loses access
+ALTER TABLE repositories ADD COLUMN IF NOT EXISTS installation_id BIGINT;
+ALTER TABLE repositories ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS idx_repositories_installation
+ON repositories(installation_id);
@@ -0,0 +1,3 @@
+-- The statuses of jobs::JobStatus
+ALTER TABLE try_merge_jobs ADD CONSTRAINT try_merge_jobs_status_check
+CHECK (status IN ('pending', 'running', 'completed', 'failed', 'cancelled'));
@@ -0,0 +1,22 @@
+-- Append-only history of commands, pushes and status changes. Rows are never updated or
+-- deleted, and outlive the jobs and queue entries they refer to.
+CREATE TABLE IF NOT EXISTS audit_events (
+    id UUID PRIMARY KEY,
+    repository_id BIGINT NOT NULL,
+    pr_number INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    actor TEXT,
+    subject_id UUID,
+    command TEXT,
+    from_status TEXT,
+    to_status TEXT,
+    sha TEXT,
+    message TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_events_repo_pr
+ON audit_events(repository_id, pr_number, created_at);
+
+CREATE INDEX IF NOT EXISTS idx_audit_events_repo_actor
+ON audit_events(repository_id, actor, created_at);
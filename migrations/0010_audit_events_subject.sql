@@ -0,0 +1,3 @@
+-- The history of one try job or merge queue entry, shown by the API
+CREATE INDEX IF NOT EXISTS idx_audit_events_subject
+ON audit_events(subject_id, created_at);
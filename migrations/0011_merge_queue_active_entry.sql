@@ -0,0 +1,12 @@
+-- A PR has at most one active queue entry. Duplicates left by concurrent approvals
+-- are cancelled, keeping the newest one, before the next migration enforces it.
+UPDATE merge_queue AS duplicates
+SET status = 'cancelled', error_message = 'Duplicate queue entry', batch_id = NULL
+WHERE status IN ('queued', 'testing')
+  AND EXISTS (
+    SELECT 1 FROM merge_queue AS newer
+    WHERE newer.repository_id = duplicates.repository_id
+      AND newer.pr_number = duplicates.pr_number
+      AND newer.status IN ('queued', 'testing')
+      AND (newer.created_at, newer.id) > (duplicates.created_at, duplicates.id)
+  );
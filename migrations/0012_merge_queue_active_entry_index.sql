@@ -0,0 +1,4 @@
+-- A separate migration from 0011, since CockroachDB rejects schema changes after
+-- writes in the same transaction
+CREATE UNIQUE INDEX IF NOT EXISTS idx_merge_queue_active_pr
+ON merge_queue(repository_id, pr_number) WHERE status IN ('queued', 'testing');
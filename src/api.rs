@@ -0,0 +1,291 @@
+// api.rs
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    audit::AuditEvent,
+    jobs::{JobRecord, JobStatus},
+    queue::{QueueEntry, QueueStatus},
+    AppState,
+};
+
+const DEFAULT_PER_PAGE: i64 = 30;
+const MAX_PER_PAGE: i64 = 100;
+
+// Read-only JSON endpoints for dashboards and tooling, behind `API_TOKEN`. They expose
+// private repositories and the server is reachable from the internet for webhooks, so
+// they are disabled while no token is set.
+pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/api/repos/:owner/:repo/queue", get(list_queue))
+        .route("/api/repos/:owner/:repo/jobs", get(list_jobs))
+        .route("/api/jobs/:id", get(get_job))
+        .route_layer(middleware::from_fn_with_state(state, require_token))
+}
+
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        error!("API request failed: {}", e);
+        ApiError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal error".to_string(),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ListParams {
+    status: Option<String>,
+    pr: Option<i32>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+impl ListParams {
+    // The page, its size and the offset of its first row
+    fn window(&self) -> (i64, i64, i64) {
+        let page = self.page.unwrap_or(1).max(1);
+        let per_page = self
+            .per_page
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE);
+        (page, per_page, (page - 1).saturating_mul(per_page))
+    }
+}
+
+async fn require_token(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(token) = &state.config.api_token else {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            "the API is disabled until API_TOKEN is set".to_string(),
+        ));
+    };
+
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or("");
+
+    // Comparing digests keeps the comparison time independent of the token
+    if Sha256::digest(provided.as_bytes()) != Sha256::digest(token.as_bytes()) {
+        return Err(ApiError(
+            StatusCode::UNAUTHORIZED,
+            "missing or invalid bearer token".to_string(),
+        ));
+    }
+
+    Ok(next.run(request).await)
+}
+
+async fn list_queue(
+    State(state): State<Arc<AppState>>,
+    Path((owner, repo)): Path<(String, String)>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<Value>, ApiError> {
+    let repo_id = repository_id(&state, &owner, &repo).await?;
+    // Without a status, the PRs still waiting to land are listed
+    let statuses = match params.status.as_deref() {
+        None => QueueStatus::ACTIVE.to_vec(),
+        Some(status) => vec![QueueStatus::parse(status).ok_or_else(|| {
+            let known: Vec<&str> = QueueStatus::ALL.iter().map(QueueStatus::as_str).collect();
+            invalid_status(status, &known)
+        })?],
+    };
+
+    let (page, per_page, offset) = params.window();
+    let entries = state
+        .db
+        .list_queue_entries(repo_id, &statuses, params.pr, per_page + 1, offset)
+        .await?;
+
+    Ok(Json(paginated(
+        entries.iter().map(queue_entry_json).collect(),
+        page,
+        per_page,
+    )))
+}
+
+async fn list_jobs(
+    State(state): State<Arc<AppState>>,
+    Path((owner, repo)): Path<(String, String)>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<Value>, ApiError> {
+    let repo_id = repository_id(&state, &owner, &repo).await?;
+    let status = match params.status.as_deref() {
+        None => None,
+        Some(status) => Some(JobStatus::parse(status).ok_or_else(|| {
+            let known: Vec<&str> = JobStatus::ALL.iter().map(JobStatus::as_str).collect();
+            invalid_status(status, &known)
+        })?),
+    };
+
+    let (page, per_page, offset) = params.window();
+    let jobs = state
+        .db
+        .list_try_merge_jobs(repo_id, status, params.pr, per_page + 1, offset)
+        .await?;
+
+    Ok(Json(paginated(
+        jobs.iter().map(job_json).collect(),
+        page,
+        per_page,
+    )))
+}
+
+// The job with every status change it went through
+async fn get_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Value>, ApiError> {
+    let Some(job) = state.db.get_try_merge_job(id).await? else {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("no try job {}", id),
+        ));
+    };
+    let history = state.db.get_subject_history(id).await?;
+
+    let mut body = job_json(&job);
+    body["history"] = history.iter().map(event_json).collect();
+
+    Ok(Json(body))
+}
+
+async fn repository_id(state: &AppState, owner: &str, repo: &str) -> Result<i64, ApiError> {
+    let full_name = format!("{}/{}", owner, repo);
+    match state.db.get_repository_by_name(&full_name).await? {
+        Some(repository) => Ok(repository.id),
+        None => Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("unknown repository {}", full_name),
+        )),
+    }
+}
+
+fn invalid_status(status: &str, known: &[&str]) -> ApiError {
+    ApiError(
+        StatusCode::BAD_REQUEST,
+        format!(
+            "unknown status `{}`, expected one of {}",
+            status,
+            known.join(", ")
+        ),
+    )
+}
+
+// `items` holds at most `per_page` of the rows, which were fetched with one extra
+fn paginated(mut items: Vec<Value>, page: i64, per_page: i64) -> Value {
+    let has_more = items.len() as i64 > per_page;
+    items.truncate(per_page as usize);
+
+    json!({
+        "items": items,
+        "page": page,
+        "per_page": per_page,
+        "has_more": has_more,
+    })
+}
+
+fn job_json(record: &JobRecord) -> Value {
+    let job = &record.job;
+    json!({
+        "id": job.id,
+        "repository_id": job.repository_id,
+        "pr_number": job.pr_number,
+        "branch_name": job.branch_name,
+        "status": job.status,
+        "head_sha": record.head_sha,
+        "attempts": record.attempts,
+        "lease_owner": record.lease_owner,
+        "error_message": job.error_message,
+        "created_at": job.created_at,
+        "updated_at": job.updated_at,
+    })
+}
+
+fn queue_entry_json(entry: &QueueEntry) -> Value {
+    json!({
+        "id": entry.id,
+        "pr_number": entry.pr_number,
+        "approved_by": entry.approved_by,
+        "priority": entry.priority,
+        "rollup": entry.rollup.as_str(),
+        "status": entry.status.as_str(),
+        "batch_id": entry.batch_id,
+        "head_sha": entry.head_sha,
+        "error_message": entry.error_message,
+        "created_at": entry.created_at,
+        "updated_at": entry.updated_at,
+    })
+}
+
+fn event_json(event: &AuditEvent) -> Value {
+    json!({
+        "kind": event.kind.as_str(),
+        "actor": event.actor,
+        "command": event.command,
+        "from_status": event.from_status,
+        "to_status": event.to_status,
+        "sha": event.sha,
+        "message": event.message,
+        "created_at": event.created_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(page: Option<i64>, per_page: Option<i64>) -> (i64, i64, i64) {
+        ListParams {
+            status: None,
+            pr: None,
+            page,
+            per_page,
+        }
+        .window()
+    }
+
+    #[test]
+    fn clamps_the_page_window() {
+        assert_eq!(window(None, None), (1, DEFAULT_PER_PAGE, 0));
+        assert_eq!(window(Some(3), Some(10)), (3, 10, 20));
+        assert_eq!(window(Some(0), Some(0)), (1, 1, 0));
+        assert_eq!(window(Some(-5), Some(1000)), (1, MAX_PER_PAGE, 0));
+    }
+
+    #[test]
+    fn saturates_the_offset_of_huge_pages() {
+        assert_eq!(
+            window(Some(i64::MAX), Some(MAX_PER_PAGE)),
+            (i64::MAX, MAX_PER_PAGE, i64::MAX)
+        );
+    }
+}
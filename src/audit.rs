@@ -0,0 +1,124 @@
+// audit.rs
+use chrono::{DateTime, Utc};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    // A command from a PR comment, including refused and malformed ones
+    Command,
+    // New commits pushed to a PR
+    Push,
+    // A try job changing status
+    TryJob,
+    // A merge queue entry changing status or head commit
+    MergeQueue,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Command => "command",
+            EventKind::Push => "push",
+            EventKind::TryJob => "try_job",
+            EventKind::MergeQueue => "merge_queue",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "command" => Some(EventKind::Command),
+            "push" => Some(EventKind::Push),
+            "try_job" => Some(EventKind::TryJob),
+            "merge_queue" => Some(EventKind::MergeQueue),
+            _ => None,
+        }
+    }
+}
+
+// One row of the append-only `audit_events` table. Events are never updated or deleted,
+// so a PR's history survives its jobs and queue entries being replaced.
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub id: Uuid,
+    pub repository_id: i64,
+    pub pr_number: i32,
+    pub kind: EventKind,
+    // The GitHub user behind the event, None for the bot's own doing
+    pub actor: Option<String>,
+    // The try job or merge queue entry the event is about
+    pub subject_id: Option<Uuid>,
+    pub command: Option<String>,
+    pub from_status: Option<String>,
+    pub to_status: Option<String>,
+    pub sha: Option<String>,
+    // Error details, refusal reasons and other context
+    pub message: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AuditEvent {
+    pub fn new(kind: EventKind, repository_id: i64, pr_number: i32) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            repository_id,
+            pr_number,
+            kind,
+            actor: None,
+            subject_id: None,
+            command: None,
+            from_status: None,
+            to_status: None,
+            sha: None,
+            message: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    // Logins are recorded lowercased since GitHub compares them case-insensitively
+    pub fn actor(mut self, actor: &str) -> Self {
+        self.actor = Some(actor.to_lowercase());
+        self
+    }
+
+    pub fn subject(mut self, id: Uuid) -> Self {
+        self.subject_id = Some(id);
+        self
+    }
+
+    pub fn command(mut self, command: impl ToString) -> Self {
+        self.command = Some(command.to_string());
+        self
+    }
+
+    pub fn transition(mut self, from: Option<&str>, to: &str) -> Self {
+        self.from_status = from.map(str::to_string);
+        self.to_status = Some(to.to_string());
+        self
+    }
+
+    pub fn sha(mut self, sha: Option<&str>) -> Self {
+        self.sha = sha.map(str::to_string);
+        self
+    }
+
+    pub fn message(mut self, message: Option<&str>) -> Self {
+        self.message = message.map(str::to_string);
+        self
+    }
+}
+
+// Records an event that is not part of a status change. Failing to record it is logged
+// rather than failing the work it describes.
+pub async fn record(state: &AppState, event: AuditEvent) {
+    if let Err(e) = state.db.record_event(&event).await {
+        error!(
+            "Could not record {} event for PR {}: {}",
+            event.kind.as_str(),
+            event.pr_number,
+            e
+        );
+    }
+}
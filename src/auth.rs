@@ -0,0 +1,188 @@
+// auth.rs
+use anyhow::Result;
+use github_merge_bot::Repository;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, sync::Arc};
+use tokio::{
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+use crate::{github::GitHubClient, repo_config::RepoConfig, AppState};
+
+// Repository roles in increasing order of access
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    None,
+    Read,
+    Triage,
+    Write,
+    Maintain,
+    Admin,
+}
+
+impl Role {
+    fn from_github(role: &str) -> Self {
+        match role {
+            "admin" => Role::Admin,
+            "maintain" => Role::Maintain,
+            "write" => Role::Write,
+            "triage" => Role::Triage,
+            "read" => Role::Read,
+            _ => Role::None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::None => write!(f, "no"),
+            Role::Read => write!(f, "read"),
+            Role::Triage => write!(f, "triage"),
+            Role::Write => write!(f, "write"),
+            Role::Maintain => write!(f, "maintain"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+// Built-in minimum role per command, overridable with `[permissions]` in the repository config
+pub fn required_role(repo_config: &RepoConfig, command: &str) -> Role {
+    if let Some(role) = repo_config.permissions.get(command) {
+        return *role;
+    }
+
+    match command {
+        "help" => Role::None,
+        "try" | "try-merge" | "cancel" | "r-" => Role::Write,
+        "r+" | "r" | "delegate" | "delegate-" => Role::Maintain,
+        _ => Role::Write,
+    }
+}
+
+// Role of each (repository, lowercased login), with when it was fetched
+type Roles = HashMap<(String, String), (Role, Instant)>;
+
+#[derive(Debug, Clone)]
+pub struct PermissionCache {
+    roles: Arc<RwLock<Roles>>,
+    ttl: Duration,
+}
+
+impl PermissionCache {
+    pub fn new(ttl_secs: u64) -> Self {
+        Self {
+            roles: Arc::new(RwLock::new(HashMap::new())),
+            ttl: Duration::from_secs(ttl_secs),
+        }
+    }
+
+    pub async fn role(&self, github: &GitHubClient, repo: &str, user: &str) -> Result<Role> {
+        let key = (repo.to_string(), user.to_lowercase());
+
+        if let Some((role, fetched_at)) = self.roles.read().await.get(&key) {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(*role);
+            }
+        }
+
+        let role = github
+            .get_collaborator_permission(repo, user)
+            .await?
+            .map(|role| Role::from_github(&role))
+            .unwrap_or(Role::None);
+        debug!("{} has {} access to {}", user, role, repo);
+
+        let mut roles = self.roles.write().await;
+        roles.retain(|_, (_, fetched_at)| fetched_at.elapsed() < self.ttl);
+        roles.insert(key, (role, Instant::now()));
+
+        Ok(role)
+    }
+}
+
+// Commands a delegate may run on the PR they were delegated. `r=<user>` is not one of
+// them: a delegate approves as themselves.
+const DELEGATED_COMMANDS: &[&str] = &["r+", "try", "try-merge", "cancel", "r-"];
+
+// Returns the reason the user may not run the command on the PR, or None when they may.
+// A non-empty reviewer list in the repository config replaces the role check for approvals
+// and delegation, and users delegated on the PR may approve and try it regardless.
+pub async fn check_permission(
+    state: &AppState,
+    repo: &Repository,
+    repo_config: &RepoConfig,
+    pr_number: i32,
+    user: &str,
+    command: &str,
+) -> Result<Option<String>> {
+    let refusal = if matches!(command, "r+" | "r" | "delegate" | "delegate-")
+        && !repo_config.reviewers.is_empty()
+    {
+        if repo_config.is_reviewer(user) {
+            return Ok(None);
+        }
+        format!("@{} is not a reviewer of this repository.", user)
+    } else {
+        let required = required_role(repo_config, command);
+        let role = state
+            .permissions
+            .role(&state.github, &repo.full_name, user)
+            .await?;
+
+        if role >= required {
+            return Ok(None);
+        }
+        format!(
+            "@{} needs {} access to use `{}` (has {} access).",
+            user, required, command, role
+        )
+    };
+
+    if DELEGATED_COMMANDS.contains(&command)
+        && state.db.is_delegated(repo.id, pr_number, user).await?
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(refusal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_built_in_roles_by_default() {
+        let config = RepoConfig::default();
+        assert_eq!(required_role(&config, "help"), Role::None);
+        assert_eq!(required_role(&config, "try"), Role::Write);
+        assert_eq!(required_role(&config, "r-"), Role::Write);
+        assert_eq!(required_role(&config, "r+"), Role::Maintain);
+        assert_eq!(required_role(&config, "r"), Role::Maintain);
+        assert_eq!(required_role(&config, "delegate-"), Role::Maintain);
+        assert_eq!(required_role(&config, "unknown"), Role::Write);
+    }
+
+    #[test]
+    fn lets_the_repository_config_override_roles() {
+        let mut config = RepoConfig::default();
+        config.permissions.insert("r+".to_string(), Role::Write);
+        config.permissions.insert("try".to_string(), Role::Admin);
+
+        assert_eq!(required_role(&config, "r+"), Role::Write);
+        assert_eq!(required_role(&config, "try"), Role::Admin);
+        assert_eq!(required_role(&config, "r"), Role::Maintain);
+    }
+
+    #[test]
+    fn orders_roles_by_access() {
+        assert!(Role::Admin > Role::Maintain);
+        assert!(Role::Write > Role::Triage);
+        assert_eq!(Role::from_github("maintain"), Role::Maintain);
+        assert_eq!(Role::from_github("pull"), Role::None);
+    }
+}
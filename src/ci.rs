@@ -0,0 +1,583 @@
+// ci.rs
+use anyhow::Result;
+use github_merge_bot::Repository;
+use std::{collections::HashMap, fmt, sync::Arc};
+use tokio::{
+    sync::{watch, Mutex},
+    time::{sleep, Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    github::{CheckRun, CheckRunOutput, CheckSuite, CommitStatus},
+    AppState,
+};
+
+// Check runs the bot reports its own verdicts with, on the PR head commit
+pub const TRY_CHECK_NAME: &str = "merge-bot/try";
+pub const MERGE_CHECK_NAME: &str = "merge-bot/merge";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CiState {
+    Pending,
+    Success,
+    Failure,
+}
+
+impl fmt::Display for CiState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CiState::Pending => write!(f, "pending"),
+            CiState::Success => write!(f, "success"),
+            CiState::Failure => write!(f, "failure"),
+        }
+    }
+}
+
+// A single commit status context or check run, with GitHub's own wording in `conclusion`
+// (e.g. "neutral", "skipped", "cancelled", "timed_out") kept for reporting
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub name: String,
+    pub state: CiState,
+    pub conclusion: String,
+    pub target_url: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CiReport {
+    pub sha: String,
+    pub state: CiState,
+    pub checks: Vec<CheckResult>,
+}
+
+impl CiReport {
+    pub fn failing(&self) -> impl Iterator<Item = &CheckResult> {
+        self.checks
+            .iter()
+            .filter(|check| check.state == CiState::Failure)
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &CheckResult> {
+        self.checks
+            .iter()
+            .filter(|check| check.state == CiState::Pending)
+    }
+
+    // e.g. "failure ([test](https://...): timed_out, lint: failure)", listing the
+    // failing checks, or the pending and missing ones while nothing has failed
+    pub fn summary(&self) -> String {
+        let checks: Vec<String> = match self.state {
+            CiState::Failure => self.failing().map(describe_check).collect(),
+            CiState::Pending => self.pending().map(describe_check).collect(),
+            CiState::Success => Vec::new(),
+        };
+
+        if checks.is_empty() {
+            self.state.to_string()
+        } else {
+            format!("{} ({})", self.state, checks.join(", "))
+        }
+    }
+
+    // Markdown table of every check with its state and a link to its details,
+    // failing checks first
+    pub fn checks_table(&self) -> String {
+        let mut checks: Vec<&CheckResult> = self.checks.iter().collect();
+        checks.sort_by_key(|check| match check.state {
+            CiState::Failure => 0,
+            CiState::Pending => 1,
+            CiState::Success => 2,
+        });
+
+        let mut lines = vec![
+            "| Check | State | Details |".to_string(),
+            "| --- | --- | --- |".to_string(),
+        ];
+        for check in checks {
+            let icon = match check.state {
+                CiState::Success => ":white_check_mark:",
+                CiState::Failure => ":x:",
+                CiState::Pending => ":hourglass:",
+            };
+            let details = check
+                .target_url
+                .as_ref()
+                .map(|url| format!("[details]({})", url))
+                .unwrap_or_default();
+            lines.push(format!(
+                "| {} | {} {} | {} |",
+                check.name.replace('|', "\\|"),
+                icon,
+                check.conclusion,
+                details
+            ));
+        }
+
+        lines.join("\n")
+    }
+}
+
+// Wakes up jobs waiting on a commit when GitHub reports CI activity for it
+#[derive(Debug, Clone, Default)]
+pub struct CiWatcher {
+    commits: Arc<Mutex<HashMap<String, watch::Sender<u64>>>>,
+}
+
+impl CiWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn subscribe(&self, sha: &str) -> watch::Receiver<u64> {
+        let mut commits = self.commits.lock().await;
+        commits
+            .entry(sha.to_string())
+            .or_insert_with(|| watch::channel(0).0)
+            .subscribe()
+    }
+
+    pub async fn notify(&self, sha: &str) {
+        let mut commits = self.commits.lock().await;
+        if let Some(sender) = commits.get(sha) {
+            sender.send_modify(|events| *events += 1);
+        }
+        commits.retain(|_, sender| sender.receiver_count() > 0);
+    }
+}
+
+fn describe_check(check: &CheckResult) -> String {
+    match &check.target_url {
+        Some(url) => format!("[{}]({}): {}", check.name, url, check.conclusion),
+        None => format!("{}: {}", check.name, check.conclusion),
+    }
+}
+
+// Folds legacy commit statuses, check runs and check suites into one verdict.
+// Any failure fails the commit right away; it only succeeds once something has
+// reported and nothing is still running. When `required` is not empty only the
+// named status contexts and check runs count, and the ones that have not
+// reported yet are listed as "missing".
+pub fn evaluate(
+    sha: &str,
+    statuses: &[CommitStatus],
+    check_suites: &[CheckSuite],
+    check_runs: &[CheckRun],
+    required: &[String],
+) -> CiReport {
+    let mut checks = Vec::new();
+
+    for status in statuses {
+        let state = match status.state.as_str() {
+            "success" => CiState::Success,
+            "failure" | "error" => CiState::Failure,
+            _ => CiState::Pending,
+        };
+        checks.push(CheckResult {
+            name: status.context.clone(),
+            state,
+            conclusion: status.state.clone(),
+            target_url: status.target_url.clone(),
+        });
+    }
+
+    for run in check_runs {
+        // The bot's own verdicts never count towards the commit's CI
+        if run.name == TRY_CHECK_NAME || run.name == MERGE_CHECK_NAME {
+            continue;
+        }
+
+        let conclusion = match run.conclusion.as_deref() {
+            Some(conclusion) if run.status == "completed" => conclusion,
+            _ => run.status.as_str(),
+        };
+        let state = match conclusion {
+            "success" | "neutral" | "skipped" => CiState::Success,
+            "failure" | "cancelled" | "timed_out" | "action_required" | "stale"
+            | "startup_failure" => CiState::Failure,
+            _ => CiState::Pending,
+        };
+        checks.push(CheckResult {
+            name: run.name.clone(),
+            state,
+            conclusion: conclusion.to_string(),
+            target_url: run.html_url.clone(),
+        });
+    }
+
+    // Suites that have started creating runs but whose runs are not listed yet.
+    // Suites without any runs are skipped, GitHub leaves those queued forever for
+    // apps that never report on the commit.
+    let expected_runs: i64 = check_suites
+        .iter()
+        .map(|suite| suite.latest_check_runs_count.unwrap_or(0))
+        .sum();
+    if (check_runs.len() as i64) < expected_runs {
+        for suite in check_suites {
+            if suite.latest_check_runs_count.unwrap_or(0) == 0
+                || suite.status.as_deref() == Some("completed")
+            {
+                continue;
+            }
+
+            let name = suite
+                .app
+                .as_ref()
+                .map(|app| app.slug.clone().unwrap_or_else(|| app.name.clone()))
+                .unwrap_or_else(|| "check suite".to_string());
+            checks.push(CheckResult {
+                name,
+                state: CiState::Pending,
+                conclusion: suite.status.clone().unwrap_or_else(|| "queued".to_string()),
+                target_url: None,
+            });
+        }
+    }
+
+    if !required.is_empty() {
+        checks.retain(|check| required.contains(&check.name));
+        for name in required {
+            if !checks.iter().any(|check| &check.name == name) {
+                checks.push(CheckResult {
+                    name: name.clone(),
+                    state: CiState::Pending,
+                    conclusion: "missing".to_string(),
+                    target_url: None,
+                });
+            }
+        }
+    }
+
+    let state = if checks.iter().any(|check| check.state == CiState::Failure) {
+        CiState::Failure
+    } else if checks.is_empty() || checks.iter().any(|check| check.state == CiState::Pending) {
+        CiState::Pending
+    } else {
+        CiState::Success
+    };
+
+    CiReport {
+        sha: sha.to_string(),
+        state,
+        checks,
+    }
+}
+
+pub async fn get_ci_report(
+    state: &AppState,
+    repo: &Repository,
+    sha: &str,
+    required: &[String],
+) -> Result<CiReport> {
+    let statuses = state
+        .github
+        .get_commit_statuses(&repo.full_name, sha)
+        .await?;
+    let check_suites = state.github.get_check_suites(&repo.full_name, sha).await?;
+    let check_runs = state.github.get_check_runs(&repo.full_name, sha).await?;
+
+    Ok(evaluate(
+        sha,
+        &statuses,
+        &check_suites,
+        &check_runs,
+        required,
+    ))
+}
+
+// Creates a queued check run named `name` on the PR head commit. Like reactions,
+// the bot's check runs are best-effort: failures are logged and None is returned.
+pub async fn create_check_run(
+    state: &AppState,
+    repo: &Repository,
+    head_sha: &str,
+    name: &str,
+) -> Option<i64> {
+    match state
+        .github
+        .create_check_run(&repo.full_name, name, head_sha)
+        .await
+    {
+        Ok(check_run_id) => Some(check_run_id),
+        Err(e) => {
+            warn!(
+                "Could not create {} check run on {}@{}: {}",
+                name, repo.full_name, head_sha, e
+            );
+            None
+        }
+    }
+}
+
+pub async fn update_check_run(
+    state: &AppState,
+    repo: &Repository,
+    check_run_id: Option<i64>,
+    status: &str,
+    conclusion: Option<&str>,
+    output: Option<&CheckRunOutput>,
+) {
+    let Some(check_run_id) = check_run_id else {
+        return;
+    };
+
+    if let Err(e) = state
+        .github
+        .update_check_run(&repo.full_name, check_run_id, status, conclusion, output)
+        .await
+    {
+        warn!(
+            "Could not update check run {} on {}: {}",
+            check_run_id, repo.full_name, e
+        );
+    }
+}
+
+// Output of a completed bot check run. The checks that failed, or were still pending
+// when CI timed out, are listed with links to their details.
+pub fn check_run_output(title: &str, summary: &str, report: Option<&CiReport>) -> CheckRunOutput {
+    let text = report.and_then(|report| {
+        let checks: Vec<&CheckResult> = match report.state {
+            CiState::Failure => report.failing().collect(),
+            CiState::Pending => report.pending().collect(),
+            CiState::Success => Vec::new(),
+        };
+        let lines: Vec<String> = checks
+            .into_iter()
+            .map(|check| match &check.target_url {
+                Some(url) => format!("- [{}]({}): {}", check.name, url, check.conclusion),
+                None => format!("- {}: {}", check.name, check.conclusion),
+            })
+            .collect();
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    });
+
+    CheckRunOutput {
+        title: title.to_string(),
+        summary: summary.to_string(),
+        text,
+    }
+}
+
+// Waits until CI on the branch head reaches a final state or the repository's timeout expires.
+// Webhook events wake the wait up early, polling covers missed deliveries.
+pub async fn wait_for_ci(
+    state: &AppState,
+    repo: &Repository,
+    branch_name: &str,
+) -> Result<CiReport> {
+    let sha = state
+        .github
+        .get_branch_sha(&repo.full_name, branch_name)
+        .await?;
+
+    let repo_config = state.repo_configs.get(&state.github, repo).await?;
+    let timeout = Duration::from_secs(
+        repo_config
+            .ci_timeout_secs
+            .unwrap_or(state.config.ci_timeout_secs),
+    );
+    // Polling without a pause would hammer the GitHub API
+    let poll_interval = Duration::from_secs(state.config.ci_poll_interval_secs.max(1));
+    let deadline = Instant::now() + timeout;
+    let mut events = state.ci_watcher.subscribe(&sha).await;
+
+    info!(
+        "Waiting up to {}s for CI on {}@{}",
+        timeout.as_secs(),
+        repo.full_name,
+        sha
+    );
+
+    let mut last_report = None;
+    loop {
+        match get_ci_report(state, repo, &sha, &repo_config.required_checks).await {
+            Ok(report) if report.state != CiState::Pending => return Ok(report),
+            Ok(report) => {
+                debug!("CI still pending on {}@{}", repo.full_name, sha);
+                last_report = Some(report);
+            }
+            Err(e) => warn!(
+                "Could not read CI status of {}@{}: {}",
+                repo.full_name, sha, e
+            ),
+        }
+
+        tokio::select! {
+            _ = events.changed() => {}
+            _ = sleep(poll_interval) => {}
+            _ = tokio::time::sleep_until(deadline) => {
+                anyhow::bail!(
+                    "Timed out after {}s waiting for CI on {}: {}",
+                    timeout.as_secs(),
+                    sha,
+                    last_report
+                        .map(|report| report.summary())
+                        .unwrap_or_else(|| "status unavailable".to_string())
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::CheckSuiteApp;
+
+    fn status(context: &str, state: &str) -> CommitStatus {
+        CommitStatus {
+            context: context.to_string(),
+            state: state.to_string(),
+            target_url: None,
+        }
+    }
+
+    fn run(name: &str, status: &str, conclusion: Option<&str>) -> CheckRun {
+        CheckRun {
+            name: name.to_string(),
+            status: status.to_string(),
+            conclusion: conclusion.map(|conclusion| conclusion.to_string()),
+            html_url: None,
+        }
+    }
+
+    fn suite(status: &str, runs: i64) -> CheckSuite {
+        CheckSuite {
+            status: Some(status.to_string()),
+            conclusion: None,
+            latest_check_runs_count: Some(runs),
+            app: Some(CheckSuiteApp {
+                slug: Some("github-actions".to_string()),
+                name: "GitHub Actions".to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn is_pending_until_something_reports() {
+        let report = evaluate("abc", &[], &[], &[], &[]);
+        assert_eq!(report.state, CiState::Pending);
+
+        // Suites of apps that never create runs are ignored
+        let report = evaluate(
+            "abc",
+            &[status("ci", "success")],
+            &[suite("queued", 0)],
+            &[],
+            &[],
+        );
+        assert_eq!(report.state, CiState::Success);
+    }
+
+    #[test]
+    fn combines_statuses_and_check_runs() {
+        let report = evaluate(
+            "abc",
+            &[status("ci", "success")],
+            &[],
+            &[
+                run("test", "completed", Some("success")),
+                run("lint", "completed", Some("skipped")),
+            ],
+            &[],
+        );
+        assert_eq!(report.state, CiState::Success);
+        assert_eq!(report.checks.len(), 3);
+
+        let report = evaluate(
+            "abc",
+            &[status("ci", "pending")],
+            &[],
+            &[run("test", "in_progress", None)],
+            &[],
+        );
+        assert_eq!(report.state, CiState::Pending);
+    }
+
+    #[test]
+    fn fails_on_any_failure() {
+        let report = evaluate(
+            "abc",
+            &[status("ci", "pending")],
+            &[],
+            &[run("test", "completed", Some("timed_out"))],
+            &[],
+        );
+        assert_eq!(report.state, CiState::Failure);
+        assert_eq!(report.summary(), "failure (test: timed_out)");
+
+        let report = evaluate("abc", &[status("ci", "error")], &[], &[], &[]);
+        assert_eq!(report.state, CiState::Failure);
+    }
+
+    #[test]
+    fn ignores_the_bots_own_check_runs() {
+        let report = evaluate(
+            "abc",
+            &[status("ci", "success")],
+            &[],
+            &[run(MERGE_CHECK_NAME, "completed", Some("failure"))],
+            &[],
+        );
+        assert_eq!(report.state, CiState::Success);
+        assert_eq!(report.checks.len(), 1);
+    }
+
+    #[test]
+    fn waits_for_suites_whose_runs_are_not_listed_yet() {
+        let report = evaluate(
+            "abc",
+            &[status("ci", "success")],
+            &[suite("in_progress", 2)],
+            &[run("test", "completed", Some("success"))],
+            &[],
+        );
+        assert_eq!(report.state, CiState::Pending);
+        assert_eq!(report.summary(), "pending (github-actions: in_progress)");
+    }
+
+    #[test]
+    fn only_counts_required_checks() {
+        let required = vec!["test".to_string(), "build".to_string()];
+
+        let report = evaluate(
+            "abc",
+            &[status("flaky", "failure")],
+            &[],
+            &[run("test", "completed", Some("success"))],
+            &required,
+        );
+        assert_eq!(report.state, CiState::Pending);
+        assert_eq!(report.summary(), "pending (build: missing)");
+
+        let report = evaluate(
+            "abc",
+            &[status("flaky", "failure"), status("build", "success")],
+            &[],
+            &[run("test", "completed", Some("success"))],
+            &required,
+        );
+        assert_eq!(report.state, CiState::Success);
+    }
+
+    #[test]
+    fn lists_failing_checks_in_the_check_run_output() {
+        let mut failed = run("test", "completed", Some("failure"));
+        failed.html_url = Some("https://ci.example/1".to_string());
+        let report = evaluate(
+            "abc",
+            &[status("lint", "failure"), status("ci", "success")],
+            &[],
+            &[failed],
+            &[],
+        );
+
+        let output = check_run_output("Try build failed", "CI failed", Some(&report));
+        assert_eq!(
+            output.text.as_deref(),
+            Some("- lint: failure\n- [test](https://ci.example/1): failure")
+        );
+        assert_eq!(check_run_output("Merged", "Done", None).text, None);
+    }
+}
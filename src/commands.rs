@@ -1,25 +1,621 @@
 // commands.rs
 use regex::Regex;
+use std::fmt;
+
+// Every command with its arguments and a description, as listed by `help`
+pub const COMMANDS: &[(&str, &str, &str)] = &[
+    (
+        "try",
+        " [<sha>]",
+        "Build the PR merged into its base branch on a try branch",
+    ),
+    (
+        "try-merge",
+        " [<sha>]",
+        "Same as `try`, on a separate try-merge branch",
+    ),
+    (
+        "r+",
+        " [<sha>] [p=<n>] [rollup=never|maybe|always]",
+        "Approve the PR and add it to the merge queue",
+    ),
+    (
+        "r",
+        "=<user> [<sha>] [p=<n>] [rollup=never|maybe|always]",
+        "Approve the PR on behalf of `<user>`",
+    ),
+    (
+        "delegate",
+        "=<user>",
+        "Let `<user>` approve, try and cancel this PR",
+    ),
+    ("delegate-", "", "Revoke delegations on this PR"),
+    (
+        "cancel",
+        "",
+        "Stop the PR's try build or merge and remove it from the merge queue",
+    ),
+    ("r-", "", "Same as `cancel`"),
+    ("help", "", "List the commands you can use on this PR"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollupMode {
+    // Small changes, picked before `Maybe` PRs to fill a batch
+    Always,
+    // May be batched with other PRs
+    Maybe,
+    // Always tested and landed on its own
+    Never,
+}
+
+impl RollupMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RollupMode::Always => "always",
+            RollupMode::Maybe => "maybe",
+            RollupMode::Never => "never",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "always" => Some(RollupMode::Always),
+            "maybe" => Some(RollupMode::Maybe),
+            "never" => Some(RollupMode::Never),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    // The optional SHA pins the build to that head commit of the PR
+    Try(Option<String>),
+    TryMerge(Option<String>),
+    Approve {
+        // Who the approval is recorded for, the commenter when None
+        approver: Option<String>,
+        sha: Option<String>,
+        priority: Option<i32>,
+        rollup: Option<RollupMode>,
+    },
+    Delegate(String),
+    Undelegate,
+    Cancel,
+    Help,
+}
+
+impl Command {
+    // Name used for permission checks
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Try(_) => "try",
+            Command::TryMerge(_) => "try-merge",
+            // Approving on behalf of someone else is its own permission
+            Command::Approve {
+                approver: Some(_), ..
+            } => "r",
+            Command::Approve { approver: None, .. } => "r+",
+            Command::Delegate(_) => "delegate",
+            Command::Undelegate => "delegate-",
+            Command::Cancel => "cancel",
+            Command::Help => "help",
+        }
+    }
+}
+
+// The command as it would be written after the mention, e.g. "r=alice 1a2b3c4 p=1"
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Try(sha) | Command::TryMerge(sha) => {
+                write!(f, "{}", self.name())?;
+                if let Some(sha) = sha {
+                    write!(f, " {}", sha)?;
+                }
+                Ok(())
+            }
+            Command::Approve {
+                approver,
+                sha,
+                priority,
+                rollup,
+            } => {
+                match approver {
+                    Some(approver) => write!(f, "r={}", approver)?,
+                    None => write!(f, "r+")?,
+                }
+                if let Some(sha) = sha {
+                    write!(f, " {}", sha)?;
+                }
+                if let Some(priority) = priority {
+                    write!(f, " p={}", priority)?;
+                }
+                if let Some(rollup) = rollup {
+                    write!(f, " rollup={}", rollup.as_str())?;
+                }
+                Ok(())
+            }
+            Command::Delegate(user) => write!(f, "delegate={}", user),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+// e.g. "@bot r+ [p=<n>] [rollup=never|maybe|always]"
+pub fn usage(bot_name: &str, command: &str) -> Option<String> {
+    COMMANDS
+        .iter()
+        .find(|(name, _, _)| *name == command)
+        .map(|(name, arguments, _)| format!("@{} {}{}", bot_name, name, arguments))
+}
+
+// Closest known command within a typo, or two for longer words, for "did you mean"
+// replies. Two typos away from a short command is most English words, e.g. `the`.
+pub fn suggest(command: &str) -> Option<&'static str> {
+    let max_distance = if command.chars().count() <= 4 { 1 } else { 2 };
+    COMMANDS
+        .iter()
+        .map(|(name, _, _)| (*name, edit_distance(command, name)))
+        .filter(|(name, distance)| *distance <= max_distance && *distance < name.len())
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(name, _)| name)
+}
+
+// Edits needed to turn one word into the other, counting swapped neighbouring letters
+// (`tyr`) as one typo
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut before: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            let mut distance = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+            if i > 0 && j > 0 && *a_char == b[j - 1] && a[i - 1] == *b_char {
+                distance = distance.min(before[j - 1] + 1);
+            }
+            current.push(distance);
+        }
+        before = std::mem::replace(&mut previous, current);
+    }
+
+    previous[b.len()]
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    Unknown(String),
+    Malformed { command: String, reason: String },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(command) => write!(f, "unknown command `{}`", command),
+            CommandError::Malformed { command, reason } => {
+                write!(f, "malformed `{}`: {}", command, reason)
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct CommandProcessor {
     bot_mention_regex: Regex,
+    inline_code_regex: Regex,
 }
 
 impl CommandProcessor {
-    pub fn new() -> Self {
-        // Matches @bot followed by a command
-        let bot_mention_regex = Regex::new(r"@bot\s+(\w+)").unwrap();
+    // Aliases are extra names the bot answers to, e.g. while migrating from an old login
+    pub fn new(bot_name: &str, aliases: &[String]) -> Self {
+        // Longest first, since the first alternative that matches wins: with `bot` and
+        // `bot-old`, `@bot-old` has to match the alias rather than `bot`
+        let mut names: Vec<&str> = std::iter::once(bot_name)
+            .chain(aliases.iter().map(|alias| alias.as_str()))
+            .collect();
+        names.sort_by_key(|name| std::cmp::Reverse(name.len()));
+        let names: Vec<String> = names.into_iter().map(regex::escape).collect();
+
+        // Matches @<bot name> as a whole login, case-insensitively. Logins may contain
+        // dashes and end in a non-word character (`merge-bot[bot]`), so the end of the
+        // name is checked explicitly rather than with `\b`.
+        let bot_mention_regex = Regex::new(&format!(
+            r"(?i)(?:^|[^\w@])@(?:{})(?:$|[^\w-])",
+            names.join("|")
+        ))
+        .unwrap();
+        let inline_code_regex = Regex::new(r"`[^`]*`").unwrap();
 
-        Self { bot_mention_regex }
+        Self {
+            bot_mention_regex,
+            inline_code_regex,
+        }
     }
 
-    pub fn parse_command(&self, comment_body: &str) -> Option<String> {
-        if let Some(captures) = self.bot_mention_regex.captures(comment_body) {
-            if let Some(command) = captures.get(1) {
-                return Some(command.as_str().to_lowercase());
+    // Parses every command addressed to the bot, in order. A mention is followed by
+    // one or more commands, each with optional `key=value` arguments, e.g.
+    // `@bot r+ p=5 rollup=never`; parsing of a mention stops at the first word that
+    // is neither, so the rest of the sentence is ignored. Quoted lines, fenced code
+    // blocks and inline code are skipped.
+    pub fn parse_commands(&self, comment_body: &str) -> Vec<Result<Command, CommandError>> {
+        let mut commands = Vec::new();
+        let mut in_code_block = false;
+
+        for line in comment_body.lines() {
+            let trimmed = line.trim_start();
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                in_code_block = !in_code_block;
+                continue;
+            }
+            if in_code_block || trimmed.starts_with('>') {
+                continue;
             }
+
+            let line = self.inline_code_regex.replace_all(line, " ");
+            let mentions: Vec<_> = self.bot_mention_regex.find_iter(&line).collect();
+            for (index, mention) in mentions.iter().enumerate() {
+                let end = mentions
+                    .get(index + 1)
+                    .map(|next| next.start())
+                    .unwrap_or(line.len());
+                parse_mention(&line[mention.end()..end], &mut commands);
+            }
+        }
+
+        commands
+    }
+}
+
+fn parse_mention(text: &str, commands: &mut Vec<Result<Command, CommandError>>) {
+    // Punctuation next to the mention belongs to the sentence, e.g. `hey @bot, r+`,
+    let text = text.trim_start_matches(|c: char| c.is_ascii_punctuation() || c.is_whitespace());
+    // and so does punctuation after a word, e.g. `@bot r+, thanks` or `@bot try.`
+    let mut words = text
+        .split_whitespace()
+        .map(|word| word.trim_end_matches(['.', ',', '!', '?', ';', ':']))
+        .filter(|word| !word.is_empty())
+        .peekable();
+
+    let Some(first) = words.next() else {
+        return;
+    };
+    if !is_command(first) {
+        // A mention in prose, e.g. `thanks @bot for the help`, is not a command
+        if looks_like_command(first) {
+            commands.push(Err(CommandError::Unknown(first.to_lowercase())));
         }
-        None
+        return;
+    }
+
+    let mut head = first;
+    loop {
+        let mut args = Vec::new();
+        while let Some(word) = words.peek() {
+            if is_command(word) || !(word.contains('=') || is_sha(word)) {
+                break;
+            }
+            args.push(*word);
+            words.next();
+        }
+
+        commands.push(build_command(head, &args));
+
+        match words.next() {
+            Some(word) if is_command(word) => head = word,
+            _ => return,
+        }
+    }
+}
+
+fn is_command(word: &str) -> bool {
+    let word = word.to_lowercase();
+    match word.split_once('=') {
+        Some((name, _)) => name == "delegate" || name == "r",
+        None => COMMANDS.iter().any(|(name, _, _)| *name == word),
+    }
+}
+
+// Whether an unknown word after the mention was meant as a command: a typo of one, or
+// shaped like one (`r++`, `rollup=never`), as opposed to the next word of a sentence
+fn looks_like_command(word: &str) -> bool {
+    let word = word.to_lowercase();
+    word.contains(['+', '=']) || word.ends_with('-') || suggest(&word).is_some()
+}
+
+// An abbreviated or full commit SHA. Bare words need a digit, so that hex-only
+// English words such as "defaced" end parsing like any other prose.
+fn is_sha(word: &str) -> bool {
+    is_sha_value(word) && word.chars().any(|c| c.is_ascii_digit())
+}
+
+fn is_sha_value(value: &str) -> bool {
+    (7..=40).contains(&value.len()) && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_login(user: &str) -> bool {
+    !user.is_empty() && user.chars().all(|c| c.is_alphanumeric() || c == '-')
+}
+
+fn build_command(head: &str, args: &[&str]) -> Result<Command, CommandError> {
+    let head = head.to_lowercase();
+    let malformed = |reason: String| CommandError::Malformed {
+        command: head.clone(),
+        reason,
+    };
+
+    // A bare SHA is shorthand for `sha=<sha>`
+    let mut arguments = Vec::new();
+    for arg in args {
+        let (key, value) = arg.split_once('=').unwrap_or(("sha", arg));
+        arguments.push((key.to_lowercase(), value.to_string()));
+    }
+
+    let mut sha = None;
+    let mut priority = None;
+    let mut rollup = None;
+    let approves = head == "r+" || head.starts_with("r=");
+    for (key, value) in &arguments {
+        match key.as_str() {
+            "sha" => {
+                if !is_sha_value(value) {
+                    return Err(malformed(format!("`{}` is not a commit SHA", value)));
+                }
+                sha = Some(value.to_lowercase());
+            }
+            "p" | "priority" if approves => {
+                priority = Some(
+                    value
+                        .parse()
+                        .map_err(|_| malformed(format!("priority `{}` is not a number", value)))?,
+                );
+            }
+            "rollup" if approves => {
+                rollup = Some(RollupMode::parse(&value.to_lowercase()).ok_or_else(|| {
+                    malformed(format!(
+                        "rollup must be always, maybe or never, not `{}`",
+                        value
+                    ))
+                })?);
+            }
+            _ => return Err(malformed(format!("unknown argument `{}`", key))),
+        }
+    }
+
+    let no_sha = |command: Command| match &sha {
+        Some(_) => Err(malformed("unknown argument `sha`".to_string())),
+        None => Ok(command),
+    };
+
+    match head.split_once('=') {
+        Some(("delegate", user)) => {
+            let user = user.trim_start_matches('@');
+            if !is_login(user) {
+                return Err(malformed(format!("`{}` is not a GitHub login", user)));
+            }
+            no_sha(Command::Delegate(user.to_string()))
+        }
+        Some(("r", user)) => {
+            let user = user.trim_start_matches('@');
+            if !is_login(user) {
+                return Err(malformed(format!("`{}` is not a GitHub login", user)));
+            }
+            Ok(Command::Approve {
+                approver: Some(user.to_string()),
+                sha,
+                priority,
+                rollup,
+            })
+        }
+        _ => match head.as_str() {
+            "try" => Ok(Command::Try(sha)),
+            "try-merge" => Ok(Command::TryMerge(sha)),
+            "r+" => Ok(Command::Approve {
+                approver: None,
+                sha,
+                priority,
+                rollup,
+            }),
+            "delegate-" => no_sha(Command::Undelegate),
+            "cancel" | "r-" => no_sha(Command::Cancel),
+            "help" => no_sha(Command::Help),
+            "delegate" => Err(malformed("expected `delegate=<user>`".to_string())),
+            "r" => Err(malformed("expected `r=<user>`".to_string())),
+            _ => Err(CommandError::Unknown(head.clone())),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: &str) -> Vec<Result<Command, CommandError>> {
+        CommandProcessor::new("bot", &["bot-old".to_string()]).parse_commands(body)
+    }
+
+    fn approve() -> Command {
+        Command::Approve {
+            approver: None,
+            sha: None,
+            priority: None,
+            rollup: None,
+        }
+    }
+
+    #[test]
+    fn parses_commands_with_arguments() {
+        assert_eq!(parse("@bot r+"), vec![Ok(approve())]);
+        assert_eq!(
+            parse("@bot r+ 1a2b3c4 p=5 rollup=never"),
+            vec![Ok(Command::Approve {
+                approver: None,
+                sha: Some("1a2b3c4".to_string()),
+                priority: Some(5),
+                rollup: Some(RollupMode::Never),
+            })]
+        );
+        assert_eq!(
+            parse("@bot r=@alice"),
+            vec![Ok(Command::Approve {
+                approver: Some("alice".to_string()),
+                sha: None,
+                priority: None,
+                rollup: None,
+            })]
+        );
+        assert_eq!(
+            parse("@bot try sha=ABCDEF0"),
+            vec![Ok(Command::Try(Some("abcdef0".to_string())))]
+        );
+        assert_eq!(
+            parse("@bot delegate=bob"),
+            vec![Ok(Command::Delegate("bob".to_string()))]
+        );
+        assert_eq!(parse("@BOT R-"), vec![Ok(Command::Cancel)]);
+    }
+
+    #[test]
+    fn parses_several_commands_per_comment() {
+        assert_eq!(
+            parse("@bot r+ try\nLooks good, @bot help"),
+            vec![Ok(approve()), Ok(Command::Try(None)), Ok(Command::Help)]
+        );
+        assert_eq!(
+            parse("@bot try @bot r+ p=1"),
+            vec![
+                Ok(Command::Try(None)),
+                Ok(Command::Approve {
+                    approver: None,
+                    sha: None,
+                    priority: Some(1),
+                    rollup: None,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_prose() {
+        assert_eq!(parse("@bot r+ once CI passes"), vec![Ok(approve())]);
+        // Hex-only words are not SHAs
+        assert_eq!(parse("@bot try defaced"), vec![Ok(Command::Try(None))]);
+    }
+
+    #[test]
+    fn skips_quotes_and_code() {
+        assert!(parse("> @bot r+").is_empty());
+        assert!(parse("```\n@bot r+\n```").is_empty());
+        assert!(parse("~~~\n@bot r+\n~~~").is_empty());
+        assert!(parse("Run `@bot r+` to approve").is_empty());
+        assert_eq!(parse("```\n@bot try\n```\n@bot r+"), vec![Ok(approve())]);
+    }
+
+    #[test]
+    fn reports_unknown_and_malformed_commands() {
+        assert_eq!(
+            parse("@bot cancle"),
+            vec![Err(CommandError::Unknown("cancle".to_string()))]
+        );
+        assert_eq!(
+            parse("@bot r+ p=high"),
+            vec![Err(CommandError::Malformed {
+                command: "r+".to_string(),
+                reason: "priority `high` is not a number".to_string(),
+            })]
+        );
+        assert_eq!(
+            parse("@bot cancel 1a2b3c4"),
+            vec![Err(CommandError::Malformed {
+                command: "cancel".to_string(),
+                reason: "unknown argument `sha`".to_string(),
+            })]
+        );
+        assert_eq!(
+            parse("@bot delegate=not/a/login"),
+            vec![Err(CommandError::Malformed {
+                command: "delegate=not/a/login".to_string(),
+                reason: "`not/a/login` is not a GitHub login".to_string(),
+            })]
+        );
+    }
+
+    #[test]
+    fn ignores_mentions_in_prose() {
+        assert!(parse("thanks @bot!").is_empty());
+        assert!(parse("thanks @bot!!").is_empty());
+        assert!(parse("@bot... never mind").is_empty());
+        assert!(parse("thanks @bot for the help").is_empty());
+        assert!(parse("cc @bot :)").is_empty());
+        assert!(parse("@bot the build is red").is_empty());
+        assert!(parse("@bot thx").is_empty());
+        assert_eq!(parse("hey @bot, r+"), vec![Ok(approve())]);
+        assert_eq!(parse("@bot: r+"), vec![Ok(approve())]);
+        assert_eq!(parse("@bot r+, thanks"), vec![Ok(approve())]);
+        assert_eq!(parse("@bot try."), vec![Ok(Command::Try(None))]);
+        assert_eq!(parse("@bot try!"), vec![Ok(Command::Try(None))]);
+        assert_eq!(
+            parse("@bot r+ p=2; merging"),
+            vec![Ok(Command::Approve {
+                approver: None,
+                sha: None,
+                priority: Some(2),
+                rollup: None,
+            })]
+        );
+        assert_eq!(
+            parse("@bot tyr"),
+            vec![Err(CommandError::Unknown("tyr".to_string()))]
+        );
+        assert_eq!(
+            parse("@bot r++"),
+            vec![Err(CommandError::Unknown("r++".to_string()))]
+        );
+    }
+
+    #[test]
+    fn matches_whole_bot_names() {
+        assert_eq!(parse("@bot-old r+"), vec![Ok(approve())]);
+        assert!(parse("@bot-older r+").is_empty());
+        assert!(parse("@bots r+").is_empty());
+        assert!(parse("me@bot r+").is_empty());
+
+        let processor = CommandProcessor::new("merge-bot[bot]", &[]);
+        assert_eq!(
+            processor.parse_commands("@merge-bot[bot] r+"),
+            vec![Ok(approve())]
+        );
+    }
+
+    #[test]
+    fn names_approvals_on_behalf_of_others_separately() {
+        let on_behalf = parse("@bot r=alice").remove(0).unwrap();
+        assert_eq!(on_behalf.name(), "r");
+        assert_eq!(approve().name(), "r+");
+    }
+
+    #[test]
+    fn suggests_close_commands() {
+        assert_eq!(suggest("tyr"), Some("try"));
+        assert_eq!(suggest("cancle"), Some("cancel"));
+        assert_eq!(suggest("delegat"), Some("delegate"));
+        assert_eq!(suggest("frobnicate"), None);
+        assert_eq!(suggest("the"), None);
+        assert_eq!(suggest("thx"), None);
+    }
+
+    #[test]
+    fn computes_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("", "try"), 3);
+        assert_eq!(edit_distance("try", "try"), 0);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("r+", "r-"), 1);
+        assert_eq!(edit_distance("tyr", "try"), 1);
+        assert_eq!(edit_distance("the", "try"), 2);
     }
 }
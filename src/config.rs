@@ -1,7 +1,7 @@
 // config.rs
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::{env, str::FromStr};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -10,6 +10,22 @@ pub struct Config {
     pub database_url: String,
     pub bind_address: String,
     pub bot_name: String,
+    pub bot_aliases: Vec<String>,
+    pub max_batch_size: usize,
+    pub ci_timeout_secs: u64,
+    pub ci_poll_interval_secs: u64,
+    pub permission_cache_ttl_secs: u64,
+    pub repo_config_cache_ttl_secs: u64,
+    // Identifies this process in job leases, stable across restarts so it can
+    // reclaim its own jobs right away
+    pub instance_id: String,
+    pub job_workers: usize,
+    pub job_lease_secs: u64,
+    pub job_max_attempts: i32,
+    pub lock_lease_secs: u64,
+    pub repo_queue_capacity: usize,
+    // Bearer token required by the read API, which is disabled when unset
+    pub api_token: Option<String>,
 }
 
 impl Config {
@@ -23,6 +39,43 @@ impl Config {
                 .unwrap_or_else(|_| "postgresql://localhost/github_bot".to_string()),
             bind_address: env::var("BIND_ADDRESS").unwrap_or_else(|_| "0.0.0.0:3000".to_string()),
             bot_name: env::var("BOT_NAME").unwrap_or_else(|_| "bot".to_string()),
+            bot_aliases: env::var("BOT_ALIASES")
+                .map(|aliases| {
+                    aliases
+                        .split(',')
+                        .map(|alias| alias.trim().trim_start_matches('@').to_string())
+                        .filter(|alias| !alias.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            max_batch_size: parse_var("MAX_BATCH_SIZE", 8)?,
+            ci_timeout_secs: parse_var("CI_TIMEOUT_SECS", 3600)?,
+            ci_poll_interval_secs: parse_var("CI_POLL_INTERVAL_SECS", 60)?,
+            permission_cache_ttl_secs: parse_var("PERMISSION_CACHE_TTL_SECS", 300)?,
+            repo_config_cache_ttl_secs: parse_var("REPO_CONFIG_CACHE_TTL_SECS", 300)?,
+            instance_id: non_empty_var("INSTANCE_ID")
+                .or_else(|| non_empty_var("HOSTNAME"))
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            job_workers: parse_var("JOB_WORKERS", 4)?,
+            job_lease_secs: parse_var("JOB_LEASE_SECS", 60)?,
+            job_max_attempts: parse_var("JOB_MAX_ATTEMPTS", 3)?,
+            lock_lease_secs: parse_var("LOCK_LEASE_SECS", 60)?,
+            repo_queue_capacity: parse_var("REPO_QUEUE_CAPACITY", 32)?,
+            api_token: non_empty_var("API_TOKEN"),
         })
     }
 }
+
+// An empty variable, e.g. left blank from `.env.example`, counts as unset
+fn non_empty_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+fn parse_var<T: FromStr>(name: &str, default: T) -> Result<T> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| anyhow::anyhow!("{} must be a number", name)),
+        Err(_) => Ok(default),
+    }
+}
@@ -1,7 +1,15 @@
 // database.rs
+use crate::{
+    audit::{AuditEvent, EventKind},
+    commands::RollupMode,
+    jobs::{JobRecord, JobStatus, LeasedJob, TransitionError},
+    migrations,
+    queue::{QueueEntry, QueueStatus},
+};
 use anyhow::Result;
-use github_merge_bot::TryMergeJob;
-use sqlx::{PgPool, Row};
+use github_merge_bot::{Repository, TryMergeJob};
+use sqlx::{postgres::PgRow, PgExecutor, PgPool, Row};
+use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct Database {
@@ -15,120 +23,983 @@ impl Database {
     }
 
     pub async fn migrate(&self) -> Result<()> {
+        migrations::run(&self.pool).await
+    }
+
+    // Records the repository as it is now, restoring it if it was deleted. A missing
+    // installation id keeps the one already recorded.
+    pub async fn upsert_repository(
+        &self,
+        repo: &Repository,
+        installation_id: Option<i64>,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        // Full names are only unique at a given time: a repository that took over the
+        // name of one renamed or deleted without us hearing about it wins the name. The
+        // other row gets a placeholder until its next webhook tells us its new name.
         sqlx::query(
             r#"
-            CREATE TABLE IF NOT EXISTS repositories (
-                id BIGINT PRIMARY KEY,
-                name TEXT NOT NULL,
-                full_name TEXT NOT NULL UNIQUE,
-                owner TEXT NOT NULL,
-                default_branch TEXT NOT NULL DEFAULT 'main',
-                created_at TIMESTAMPTZ DEFAULT NOW(),
-                updated_at TIMESTAMPTZ DEFAULT NOW()
-            )
+            UPDATE repositories SET full_name = '#' || id::TEXT, updated_at = NOW()
+            WHERE full_name = $1 AND id <> $2
             "#,
         )
-        .execute(&self.pool)
+        .bind(&repo.full_name)
+        .bind(repo.id)
+        .execute(&mut *tx)
         .await?;
 
         sqlx::query(
             r#"
-            CREATE TABLE IF NOT EXISTS try_merge_jobs (
-                id UUID PRIMARY KEY,
-                repository_id BIGINT NOT NULL,
-                pr_number INTEGER NOT NULL,
-                branch_name TEXT NOT NULL,
-                status TEXT NOT NULL DEFAULT 'pending',
-                created_at TIMESTAMPTZ DEFAULT NOW(),
-                updated_at TIMESTAMPTZ DEFAULT NOW(),
-                error_message TEXT,
-                CONSTRAINT fk_repository FOREIGN KEY (repository_id) REFERENCES repositories(id)
-            )
+            INSERT INTO repositories (id, name, full_name, owner, default_branch, installation_id)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (id) DO UPDATE SET
+                name = $2, full_name = $3, owner = $4, default_branch = $5,
+                installation_id = COALESCE($6, repositories.installation_id),
+                deleted_at = NULL, updated_at = NOW()
             "#,
         )
-        .execute(&self.pool)
+        .bind(repo.id)
+        .bind(&repo.name)
+        .bind(&repo.full_name)
+        .bind(&repo.owner)
+        .bind(&repo.default_branch)
+        .bind(installation_id)
+        .execute(&mut *tx)
         .await?;
 
-        sqlx::query(
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Soft-deletes the repositories, keeping their jobs and queue history. Returns the
+    // number of repositories deleted.
+    pub async fn delete_repositories(&self, repository_ids: &[i64]) -> Result<u64> {
+        let result = sqlx::query(
             r#"
-            CREATE INDEX IF NOT EXISTS idx_try_merge_jobs_repo_pr 
-            ON try_merge_jobs(repository_id, pr_number)
+            UPDATE repositories SET deleted_at = NOW(), updated_at = NOW()
+            WHERE id = ANY($1) AND deleted_at IS NULL
             "#,
         )
+        .bind(repository_ids)
         .execute(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(result.rows_affected())
+    }
+
+    // Soft-deletes every repository of an installation, returning their ids
+    pub async fn delete_installation_repositories(&self, installation_id: i64) -> Result<Vec<i64>> {
+        let rows = sqlx::query(
+            r#"
+            UPDATE repositories SET deleted_at = NOW(), updated_at = NOW()
+            WHERE installation_id = $1 AND deleted_at IS NULL
+            RETURNING id
+            "#,
+        )
+        .bind(installation_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(|row| row.get("id")).collect())
     }
 
-    pub async fn create_try_merge_job(&self, job: &TryMergeJob) -> Result<()> {
+    // Repositories the app is still installed on, by case-insensitive "owner/name"
+    pub async fn get_repository_by_name(&self, full_name: &str) -> Result<Option<Repository>> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, name, full_name, owner, default_branch
+            FROM repositories
+            WHERE LOWER(full_name) = LOWER($1) AND deleted_at IS NULL
+            "#,
+        )
+        .bind(full_name)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| Repository {
+            id: row.get("id"),
+            name: row.get("name"),
+            full_name: row.get("full_name"),
+            owner: row.get("owner"),
+            default_branch: row.get("default_branch"),
+        }))
+    }
+
+    pub async fn create_try_merge_job(
+        &self,
+        job: &TryMergeJob,
+        head_sha: &str,
+        comment_id: i64,
+        check_run_id: Option<i64>,
+        requested_by: &str,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
         sqlx::query(
             r#"
             INSERT INTO try_merge_jobs 
-            (id, repository_id, pr_number, branch_name, status, created_at, updated_at, error_message)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            (id, repository_id, pr_number, branch_name, status, created_at, updated_at, error_message,
+             comment_id, check_run_id, head_sha)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
             "#,
         )
-        .bind(&job.id)
-        .bind(&job.repository_id)
-        .bind(&job.pr_number)
+        .bind(job.id)
+        .bind(job.repository_id)
+        .bind(job.pr_number)
         .bind(&job.branch_name)
         .bind(&job.status)
-        .bind(&job.created_at)
-        .bind(&job.updated_at)
+        .bind(job.created_at)
+        .bind(job.updated_at)
         .bind(&job.error_message)
-        .execute(&self.pool)
+        .bind(comment_id)
+        .bind(check_run_id)
+        .bind(head_sha)
+        .execute(&mut *tx)
         .await?;
 
+        let event = AuditEvent::new(EventKind::TryJob, job.repository_id, job.pr_number)
+            .actor(requested_by)
+            .subject(job.id)
+            .transition(None, &job.status)
+            .sha(Some(head_sha));
+        insert_event(&mut *tx, &event).await?;
+
+        tx.commit().await?;
         Ok(())
     }
 
-    pub async fn update_try_merge_job(&self, job: &TryMergeJob) -> Result<()> {
-        sqlx::query(
+    // Moves the job from one status to another as a compare-and-swap: fails with a
+    // `TransitionError` when the transition table forbids it or the job is no longer in
+    // `from`, e.g. because it was cancelled while running. The error message is kept
+    // when None is given.
+    pub async fn transition_try_merge_job(
+        &self,
+        id: Uuid,
+        from: JobStatus,
+        to: JobStatus,
+        lease_owner: Option<&str>,
+        error_message: Option<&str>,
+    ) -> Result<()> {
+        let rejected = |actual| TransitionError {
+            job_id: id,
+            from,
+            to,
+            actual,
+        };
+        if !from.can_transition_to(to) {
+            return Err(rejected(None).into());
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        // Only running jobs hold a lease, so it is dropped whenever a job leaves running
+        let row = sqlx::query(
             r#"
-            UPDATE try_merge_jobs 
-            SET status = $2, updated_at = $3, error_message = $4
-            WHERE id = $1
+            UPDATE try_merge_jobs
+            SET status = $3, error_message = COALESCE($4, error_message), updated_at = NOW(),
+                lease_owner = NULL, lease_expires_at = NULL
+            WHERE id = $1 AND status = $2 AND ($5::TEXT IS NULL OR lease_owner = $5)
+            RETURNING repository_id, pr_number, head_sha
             "#,
         )
-        .bind(&job.id)
-        .bind(&job.status)
-        .bind(&job.updated_at)
-        .bind(&job.error_message)
-        .execute(&self.pool)
+        .bind(id)
+        .bind(from.as_str())
+        .bind(to.as_str())
+        .bind(error_message)
+        .bind(lease_owner)
+        .fetch_optional(&mut *tx)
         .await?;
 
+        let Some(row) = row else {
+            drop(tx);
+            let actual = sqlx::query("SELECT status FROM try_merge_jobs WHERE id = $1")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?
+                .and_then(|row| JobStatus::parse(row.get("status")));
+            return Err(rejected(actual).into());
+        };
+
+        let event = AuditEvent::new(
+            EventKind::TryJob,
+            row.get("repository_id"),
+            row.get("pr_number"),
+        )
+        .subject(id)
+        .transition(Some(from.as_str()), to.as_str())
+        .sha(row.get("head_sha"))
+        .message(error_message);
+        insert_event(&mut *tx, &event).await?;
+
+        tx.commit().await?;
         Ok(())
     }
 
+    // Cancels the PR's pending and running jobs, returning them with their check run.
+    // A worker running one of them notices when renewing its lease and stops.
+    pub async fn cancel_try_merge_jobs(
+        &self,
+        repository_id: i64,
+        pr_number: i32,
+        reason: &str,
+    ) -> Result<Vec<(TryMergeJob, Option<i64>)>> {
+        let mut tx = self.pool.begin().await?;
+
+        let rows = sqlx::query(
+            r#"
+            UPDATE try_merge_jobs AS jobs
+            SET status = $4, error_message = $3, lease_owner = NULL,
+                lease_expires_at = NULL, updated_at = NOW()
+            FROM (
+                SELECT id, status FROM try_merge_jobs
+                WHERE repository_id = $1 AND pr_number = $2 AND status = ANY($5)
+                FOR UPDATE
+            ) AS previous
+            WHERE jobs.id = previous.id
+            RETURNING jobs.id, jobs.repository_id, jobs.pr_number, jobs.branch_name, jobs.status,
+                      jobs.created_at, jobs.updated_at, jobs.error_message, jobs.check_run_id,
+                      jobs.head_sha, previous.status AS previous_status
+            "#,
+        )
+        .bind(repository_id)
+        .bind(pr_number)
+        .bind(reason)
+        .bind(JobStatus::Cancelled.as_str())
+        .bind(statuses(JobStatus::ACTIVE))
+        .fetch_all(&mut *tx)
+        .await?;
+
+        for row in &rows {
+            let event = AuditEvent::new(EventKind::TryJob, repository_id, pr_number)
+                .subject(row.get("id"))
+                .transition(row.get("previous_status"), JobStatus::Cancelled.as_str())
+                .sha(row.get("head_sha"))
+                .message(Some(reason));
+            insert_event(&mut *tx, &event).await?;
+        }
+
+        tx.commit().await?;
+        Ok(rows
+            .iter()
+            .map(|row| (try_merge_job_from_row(row), row.get("check_run_id")))
+            .collect())
+    }
+
     pub async fn get_active_jobs(&self, repository_id: i64) -> Result<Vec<TryMergeJob>> {
         let rows = sqlx::query(
             r#"
             SELECT id, repository_id, pr_number, branch_name, status, 
                    created_at, updated_at, error_message
             FROM try_merge_jobs 
-            WHERE repository_id = $1 AND status IN ('pending', 'running')
+            WHERE repository_id = $1 AND status = ANY($2)
             ORDER BY created_at DESC
             "#,
         )
         .bind(repository_id)
+        .bind(statuses(JobStatus::ACTIVE))
         .fetch_all(&self.pool)
         .await?;
 
-        let jobs = rows
-            .into_iter()
-            .map(|row| TryMergeJob {
-                id: row.get("id"),
-                repository_id: row.get("repository_id"),
-                pr_number: row.get("pr_number"),
-                branch_name: row.get("branch_name"),
-                status: row.get("status"),
-                created_at: row.get("created_at"),
-                updated_at: row.get("updated_at"),
-                error_message: row.get("error_message"),
-            })
-            .collect();
+        let jobs = rows.iter().map(try_merge_job_from_row).collect();
 
         Ok(jobs)
     }
+
+    // The repository's jobs, newest first, optionally only those in a status or of a PR
+    pub async fn list_try_merge_jobs(
+        &self,
+        repository_id: i64,
+        status: Option<JobStatus>,
+        pr_number: Option<i32>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<JobRecord>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, repository_id, pr_number, branch_name, status, created_at, updated_at,
+                   error_message, head_sha, attempts, lease_owner
+            FROM try_merge_jobs
+            WHERE repository_id = $1
+              AND ($2::TEXT IS NULL OR status = $2)
+              AND ($3::INTEGER IS NULL OR pr_number = $3)
+            ORDER BY created_at DESC, id
+            LIMIT $4 OFFSET $5
+            "#,
+        )
+        .bind(repository_id)
+        .bind(status.map(|status| status.as_str()))
+        .bind(pr_number)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(job_record_from_row).collect())
+    }
+
+    pub async fn get_try_merge_job(&self, id: Uuid) -> Result<Option<JobRecord>> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, repository_id, pr_number, branch_name, status, created_at, updated_at,
+                   error_message, head_sha, attempts, lease_owner
+            FROM try_merge_jobs
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.as_ref().map(job_record_from_row))
+    }
+
+    // Claims the oldest pending job, or a running one whose lease expired because its
+    // worker died, for `lease_secs`. SKIP LOCKED lets concurrent workers claim
+    // different jobs without waiting on each other.
+    pub async fn lease_try_merge_job(
+        &self,
+        owner: &str,
+        lease_secs: u64,
+    ) -> Result<Option<LeasedJob>> {
+        let mut tx = self.pool.begin().await?;
+
+        let row = sqlx::query(
+            r#"
+            UPDATE try_merge_jobs AS jobs
+            SET status = $4, lease_owner = $1,
+                lease_expires_at = NOW() + $2 * INTERVAL '1 second',
+                attempts = attempts + 1, updated_at = NOW()
+            FROM (
+                SELECT id, status FROM try_merge_jobs
+                WHERE status = $3 OR (status = $4 AND lease_expires_at < NOW())
+                ORDER BY created_at
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            ) AS claimed
+            WHERE jobs.id = claimed.id
+            RETURNING jobs.id, jobs.repository_id, jobs.pr_number, jobs.branch_name, jobs.status,
+                      jobs.created_at, jobs.updated_at, jobs.error_message, jobs.comment_id,
+                      jobs.check_run_id, jobs.head_sha, jobs.attempts,
+                      claimed.status AS previous_status
+            "#,
+        )
+        .bind(owner)
+        .bind(lease_secs as f64)
+        .bind(JobStatus::Pending.as_str())
+        .bind(JobStatus::Running.as_str())
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let leased = LeasedJob {
+            job: try_merge_job_from_row(&row),
+            comment_id: row.get("comment_id"),
+            check_run_id: row.get("check_run_id"),
+            head_sha: row.get("head_sha"),
+            attempts: row.get("attempts"),
+            lease_owner: owner.to_string(),
+        };
+
+        let event = AuditEvent::new(
+            EventKind::TryJob,
+            leased.job.repository_id,
+            leased.job.pr_number,
+        )
+        .subject(leased.job.id)
+        .transition(row.get("previous_status"), JobStatus::Running.as_str())
+        .sha(leased.head_sha.as_deref())
+        .message(Some(&format!(
+            "Claimed by {} (attempt {})",
+            owner, leased.attempts
+        )));
+        insert_event(&mut *tx, &event).await?;
+
+        tx.commit().await?;
+        Ok(Some(leased))
+    }
+
+    // Returns false once the lease has been lost, e.g. to another worker after expiring
+    pub async fn renew_try_merge_job_lease(
+        &self,
+        id: Uuid,
+        owner: &str,
+        lease_secs: u64,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE try_merge_jobs
+            SET lease_expires_at = NOW() + $3 * INTERVAL '1 second'
+            WHERE id = $1 AND lease_owner = $2 AND status = $4
+            "#,
+        )
+        .bind(id)
+        .bind(owner)
+        .bind(lease_secs as f64)
+        .bind(JobStatus::Running.as_str())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    // Puts jobs this instance was running before a restart back in the queue, along with
+    // jobs left running without a lease by versions that predate leasing. Lease owners
+    // are `<instance id>/<worker>`, or the bare instance id for older leases.
+    pub async fn recover_try_merge_jobs(&self, instance_id: &str) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+
+        let rows = sqlx::query(
+            r#"
+            UPDATE try_merge_jobs
+            SET status = $2, lease_owner = NULL, lease_expires_at = NULL, updated_at = NOW()
+            WHERE status = $3
+              AND (left(lease_owner, length($1) + 1) = $1 || '/'
+                   OR lease_owner = $1 OR lease_owner IS NULL)
+            RETURNING id, repository_id, pr_number, head_sha
+            "#,
+        )
+        .bind(instance_id)
+        .bind(JobStatus::Pending.as_str())
+        .bind(JobStatus::Running.as_str())
+        .fetch_all(&mut *tx)
+        .await?;
+
+        for row in &rows {
+            let event = AuditEvent::new(
+                EventKind::TryJob,
+                row.get("repository_id"),
+                row.get("pr_number"),
+            )
+            .subject(row.get("id"))
+            .transition(
+                Some(JobStatus::Running.as_str()),
+                JobStatus::Pending.as_str(),
+            )
+            .sha(row.get("head_sha"))
+            .message(Some(&format!("Requeued after {} restarted", instance_id)));
+            insert_event(&mut *tx, &event).await?;
+        }
+
+        tx.commit().await?;
+        Ok(rows.len() as u64)
+    }
+
+    // Takes the lock when it is free or its lease expired, bumping the fencing token so
+    // writes made under a previous holder's token can be told apart. Returns None while
+    // someone else holds it.
+    pub async fn acquire_lock(
+        &self,
+        repository_id: i64,
+        resource: &str,
+        owner: &str,
+        lease_secs: u64,
+    ) -> Result<Option<i64>> {
+        let row = sqlx::query(
+            r#"
+            INSERT INTO repository_locks (repository_id, resource, owner, fencing_token, expires_at)
+            VALUES ($1, $2, $3, 1, NOW() + $4 * INTERVAL '1 second')
+            ON CONFLICT (repository_id, resource) DO UPDATE
+            SET owner = excluded.owner,
+                fencing_token = repository_locks.fencing_token + 1,
+                expires_at = excluded.expires_at
+            WHERE repository_locks.expires_at < NOW()
+            RETURNING fencing_token
+            "#,
+        )
+        .bind(repository_id)
+        .bind(resource)
+        .bind(owner)
+        .bind(lease_secs as f64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| row.get("fencing_token")))
+    }
+
+    // Returns false once the lock has been taken over, e.g. after its lease expired
+    pub async fn renew_lock(
+        &self,
+        repository_id: i64,
+        resource: &str,
+        owner: &str,
+        fencing_token: i64,
+        lease_secs: u64,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE repository_locks
+            SET expires_at = NOW() + $5 * INTERVAL '1 second'
+            WHERE repository_id = $1 AND resource = $2 AND owner = $3 AND fencing_token = $4
+            "#,
+        )
+        .bind(repository_id)
+        .bind(resource)
+        .bind(owner)
+        .bind(fencing_token)
+        .bind(lease_secs as f64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    // Expires the lock rather than deleting it, so the fencing token keeps increasing
+    pub async fn release_lock(
+        &self,
+        repository_id: i64,
+        resource: &str,
+        owner: &str,
+        fencing_token: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE repository_locks
+            SET expires_at = NOW()
+            WHERE repository_id = $1 AND resource = $2 AND owner = $3 AND fencing_token = $4
+            "#,
+        )
+        .bind(repository_id)
+        .bind(resource)
+        .bind(owner)
+        .bind(fencing_token)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Frees the locks this instance held before a restart, instead of leaving them taken
+    // until their leases run out. Lock owners are `<instance id>/<holder>`.
+    pub async fn release_instance_locks(&self, instance_id: &str) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            UPDATE repository_locks
+            SET expires_at = NOW()
+            WHERE left(owner, length($1) + 1) = $1 || '/' AND expires_at > NOW()
+            "#,
+        )
+        .bind(instance_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    // Returns false without inserting when the PR already has an active entry
+    pub async fn enqueue_pull_request(&self, entry: &QueueEntry) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let inserted = sqlx::query(
+            r#"
+            INSERT INTO merge_queue 
+            (id, repository_id, pr_number, approved_by, priority, status, created_at, updated_at, error_message, rollup, comment_id, check_run_id, head_sha)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+            ON CONFLICT (repository_id, pr_number) WHERE status IN ('queued', 'testing') DO NOTHING
+            "#,
+        )
+        .bind(entry.id)
+        .bind(entry.repository_id)
+        .bind(entry.pr_number)
+        .bind(&entry.approved_by)
+        .bind(entry.priority)
+        .bind(entry.status.as_str())
+        .bind(entry.created_at)
+        .bind(entry.updated_at)
+        .bind(&entry.error_message)
+        .bind(entry.rollup.as_str())
+        .bind(entry.comment_id)
+        .bind(entry.check_run_id)
+        .bind(&entry.head_sha)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if !inserted {
+            return Ok(false);
+        }
+
+        let event = AuditEvent::new(EventKind::MergeQueue, entry.repository_id, entry.pr_number)
+            .actor(&entry.approved_by)
+            .subject(entry.id)
+            .transition(None, entry.status.as_str())
+            .sha(entry.head_sha.as_deref());
+        insert_event(&mut *tx, &event).await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // Only touches the check run, so it cannot undo a status change made meanwhile
+    pub async fn set_queue_check_run(&self, id: Uuid, check_run_id: Option<i64>) -> Result<()> {
+        sqlx::query("UPDATE merge_queue SET check_run_id = $2 WHERE id = $1")
+            .bind(id)
+            .bind(check_run_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Records an event when the entry changes status or head commit
+    pub async fn update_queue_entry(&self, entry: &QueueEntry) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let row = sqlx::query(
+            r#"
+            UPDATE merge_queue AS entries
+            SET status = $2, updated_at = $3, error_message = $4, batch_id = $5,
+                priority = $6, rollup = $7, comment_id = $8, check_run_id = $9, head_sha = $10
+            FROM (
+                SELECT id, status, head_sha FROM merge_queue WHERE id = $1 FOR UPDATE
+            ) AS previous
+            WHERE entries.id = previous.id
+            RETURNING previous.status AS previous_status, previous.head_sha AS previous_head_sha
+            "#,
+        )
+        .bind(entry.id)
+        .bind(entry.status.as_str())
+        .bind(entry.updated_at)
+        .bind(&entry.error_message)
+        .bind(entry.batch_id)
+        .bind(entry.priority)
+        .bind(entry.rollup.as_str())
+        .bind(entry.comment_id)
+        .bind(entry.check_run_id)
+        .bind(&entry.head_sha)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(row) = row {
+            let previous_status: String = row.get("previous_status");
+            let previous_head_sha: Option<String> = row.get("previous_head_sha");
+            if previous_status != entry.status.as_str() || previous_head_sha != entry.head_sha {
+                let event =
+                    AuditEvent::new(EventKind::MergeQueue, entry.repository_id, entry.pr_number)
+                        .subject(entry.id)
+                        .transition(Some(&previous_status), entry.status.as_str())
+                        .sha(entry.head_sha.as_deref())
+                        .message(entry.error_message.as_deref());
+                insert_event(&mut *tx, &event).await?;
+            }
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn get_queue_entry(
+        &self,
+        repository_id: i64,
+        pr_number: i32,
+    ) -> Result<Option<QueueEntry>> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, repository_id, pr_number, approved_by, priority, status,
+                   created_at, updated_at, error_message, batch_id, rollup, comment_id,
+                   check_run_id, head_sha
+            FROM merge_queue 
+            WHERE repository_id = $1 AND pr_number = $2 AND status IN ('queued', 'testing')
+            "#,
+        )
+        .bind(repository_id)
+        .bind(pr_number)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(queue_entry_from_row))
+    }
+
+    // Queued entries in landing order: highest priority first, then oldest approval
+    pub async fn get_merge_queue(&self, repository_id: i64) -> Result<Vec<QueueEntry>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, repository_id, pr_number, approved_by, priority, status,
+                   created_at, updated_at, error_message, batch_id, rollup, comment_id,
+                   check_run_id, head_sha
+            FROM merge_queue 
+            WHERE repository_id = $1 AND status = 'queued'
+            ORDER BY priority DESC, created_at ASC
+            "#,
+        )
+        .bind(repository_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(queue_entry_from_row).collect())
+    }
+
+    // Repositories with entries waiting in or being tested by their merge queue
+    pub async fn get_queue_repository_ids(&self) -> Result<Vec<i64>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT DISTINCT repository_id
+            FROM merge_queue
+            WHERE status IN ('queued', 'testing')
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(|row| row.get("repository_id")).collect())
+    }
+
+    // Entries in the given statuses, optionally of one PR: the batch being tested first,
+    // then in landing order
+    pub async fn list_queue_entries(
+        &self,
+        repository_id: i64,
+        statuses: &[QueueStatus],
+        pr_number: Option<i32>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<QueueEntry>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, repository_id, pr_number, approved_by, priority, status,
+                   created_at, updated_at, error_message, batch_id, rollup, comment_id,
+                   check_run_id, head_sha
+            FROM merge_queue
+            WHERE repository_id = $1 AND status = ANY($2)
+              AND ($3::INTEGER IS NULL OR pr_number = $3)
+            ORDER BY status = 'testing' DESC, priority DESC, created_at ASC, id
+            LIMIT $4 OFFSET $5
+            "#,
+        )
+        .bind(repository_id)
+        .bind(statuses.iter().map(QueueStatus::as_str).collect::<Vec<_>>())
+        .bind(pr_number)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(queue_entry_from_row).collect())
+    }
+
+    pub async fn add_delegation(
+        &self,
+        repository_id: i64,
+        pr_number: i32,
+        delegate: &str,
+        delegated_by: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO delegations (repository_id, pr_number, delegate, delegated_by)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (repository_id, pr_number, delegate)
+            DO UPDATE SET delegated_by = $4, created_at = NOW()
+            "#,
+        )
+        .bind(repository_id)
+        .bind(pr_number)
+        .bind(delegate.to_lowercase())
+        .bind(delegated_by)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Returns the number of delegations removed
+    pub async fn remove_delegations(&self, repository_id: i64, pr_number: i32) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM delegations 
+            WHERE repository_id = $1 AND pr_number = $2
+            "#,
+        )
+        .bind(repository_id)
+        .bind(pr_number)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn is_delegated(
+        &self,
+        repository_id: i64,
+        pr_number: i32,
+        user: &str,
+    ) -> Result<bool> {
+        let row = sqlx::query(
+            r#"
+            SELECT 1 FROM delegations 
+            WHERE repository_id = $1 AND pr_number = $2 AND delegate = $3
+            "#,
+        )
+        .bind(repository_id)
+        .bind(pr_number)
+        .bind(user.to_lowercase())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.is_some())
+    }
+
+    pub async fn record_event(&self, event: &AuditEvent) -> Result<()> {
+        insert_event(&self.pool, event).await
+    }
+
+    // Everything that happened on the PR, oldest first
+    pub async fn get_pr_history(
+        &self,
+        repository_id: i64,
+        pr_number: i32,
+    ) -> Result<Vec<AuditEvent>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, repository_id, pr_number, kind, actor, subject_id, command,
+                   from_status, to_status, sha, message, created_at
+            FROM audit_events
+            WHERE repository_id = $1 AND pr_number = $2
+            ORDER BY created_at, id
+            "#,
+        )
+        .bind(repository_id)
+        .bind(pr_number)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().filter_map(audit_event_from_row).collect())
+    }
+
+    // The user's most recent commands and pushes on the repository, newest first
+    pub async fn get_user_history(
+        &self,
+        repository_id: i64,
+        actor: &str,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, repository_id, pr_number, kind, actor, subject_id, command,
+                   from_status, to_status, sha, message, created_at
+            FROM audit_events
+            WHERE repository_id = $1 AND actor = $2
+            ORDER BY created_at DESC, id
+            LIMIT $3
+            "#,
+        )
+        .bind(repository_id)
+        .bind(actor.to_lowercase())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().filter_map(audit_event_from_row).collect())
+    }
+
+    // The status changes of one try job or merge queue entry, oldest first
+    pub async fn get_subject_history(&self, subject_id: Uuid) -> Result<Vec<AuditEvent>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, repository_id, pr_number, kind, actor, subject_id, command,
+                   from_status, to_status, sha, message, created_at
+            FROM audit_events
+            WHERE subject_id = $1
+            ORDER BY created_at, id
+            "#,
+        )
+        .bind(subject_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().filter_map(audit_event_from_row).collect())
+    }
+}
+
+// Events are append-only: inserted alongside the change they describe, never updated
+async fn insert_event<'e>(executor: impl PgExecutor<'e>, event: &AuditEvent) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO audit_events
+        (id, repository_id, pr_number, kind, actor, subject_id, command,
+         from_status, to_status, sha, message, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+        "#,
+    )
+    .bind(event.id)
+    .bind(event.repository_id)
+    .bind(event.pr_number)
+    .bind(event.kind.as_str())
+    .bind(&event.actor)
+    .bind(event.subject_id)
+    .bind(&event.command)
+    .bind(&event.from_status)
+    .bind(&event.to_status)
+    .bind(&event.sha)
+    .bind(&event.message)
+    .bind(event.created_at)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+// None for kinds recorded by a newer version
+fn audit_event_from_row(row: &PgRow) -> Option<AuditEvent> {
+    Some(AuditEvent {
+        id: row.get("id"),
+        repository_id: row.get("repository_id"),
+        pr_number: row.get("pr_number"),
+        kind: EventKind::parse(row.get("kind"))?,
+        actor: row.get("actor"),
+        subject_id: row.get("subject_id"),
+        command: row.get("command"),
+        from_status: row.get("from_status"),
+        to_status: row.get("to_status"),
+        sha: row.get("sha"),
+        message: row.get("message"),
+        created_at: row.get("created_at"),
+    })
+}
+
+fn statuses(statuses: &[JobStatus]) -> Vec<&'static str> {
+    statuses.iter().map(JobStatus::as_str).collect()
+}
+
+fn try_merge_job_from_row(row: &PgRow) -> TryMergeJob {
+    TryMergeJob {
+        id: row.get("id"),
+        repository_id: row.get("repository_id"),
+        pr_number: row.get("pr_number"),
+        branch_name: row.get("branch_name"),
+        status: row.get("status"),
+        created_at: row.get("created_at"),
+        updated_at: row.get("updated_at"),
+        error_message: row.get("error_message"),
+    }
+}
+
+fn job_record_from_row(row: &PgRow) -> JobRecord {
+    JobRecord {
+        job: try_merge_job_from_row(row),
+        head_sha: row.get("head_sha"),
+        attempts: row.get("attempts"),
+        lease_owner: row.get("lease_owner"),
+    }
+}
+
+fn queue_entry_from_row(row: PgRow) -> QueueEntry {
+    QueueEntry {
+        id: row.get("id"),
+        repository_id: row.get("repository_id"),
+        pr_number: row.get("pr_number"),
+        approved_by: row.get("approved_by"),
+        priority: row.get("priority"),
+        // An entry in a status this version does not know can no longer land
+        status: QueueStatus::parse(row.get("status")).unwrap_or(QueueStatus::Failed),
+        created_at: row.get("created_at"),
+        updated_at: row.get("updated_at"),
+        error_message: row.get("error_message"),
+        batch_id: row.get("batch_id"),
+        rollup: RollupMode::parse(row.get("rollup")).unwrap_or(RollupMode::Maybe),
+        comment_id: row.get("comment_id"),
+        check_run_id: row.get("check_run_id"),
+        head_sha: row.get("head_sha"),
+    }
 }
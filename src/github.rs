@@ -1,6 +1,7 @@
 // github.rs
 use anyhow::Result;
-use github_merge_bot::PullRequest;
+use base64::Engine;
+use github_merge_bot::{PullRequest, Repository};
 use reqwest::{header, Client};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -8,7 +9,6 @@ use serde_json::json;
 #[derive(Debug, Clone)]
 pub struct GitHubClient {
     client: Client,
-    token: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -43,6 +43,59 @@ struct GitHubUser {
     login: String,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommitStatus {
+    pub context: String,
+    pub state: String,
+    pub target_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CombinedStatus {
+    statuses: Vec<CommitStatus>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CheckRun {
+    pub name: String,
+    pub status: String,
+    pub conclusion: Option<String>,
+    pub html_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckRunList {
+    check_runs: Vec<CheckRun>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CheckSuite {
+    pub status: Option<String>,
+    pub conclusion: Option<String>,
+    pub latest_check_runs_count: Option<i64>,
+    pub app: Option<CheckSuiteApp>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CheckSuiteApp {
+    pub slug: Option<String>,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckSuiteList {
+    check_suites: Vec<CheckSuite>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckRunOutput {
+    pub title: String,
+    pub summary: String,
+    // Markdown details shown below the summary
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
 impl GitHubClient {
     pub fn new(token: &str) -> Self {
         let mut headers = header::HeaderMap::new();
@@ -61,10 +114,7 @@ impl GitHubClient {
 
         let client = Client::builder().default_headers(headers).build().unwrap();
 
-        Self {
-            client,
-            token: token.to_string(),
-        }
+        Self { client }
     }
 
     pub async fn get_pull_request(&self, repo: &str, pr_number: i32) -> Result<PullRequest> {
@@ -83,7 +133,7 @@ impl GitHubClient {
             title: github_pr.title,
             head_branch: github_pr.head.ref_name,
             base_branch: github_pr.base.ref_name,
-            repository: github_merge_bot::Repository {
+            repository: Repository {
                 id: github_pr.base.repo.id,
                 name: github_pr.base.repo.name,
                 full_name: github_pr.base.repo.full_name,
@@ -95,19 +145,57 @@ impl GitHubClient {
         })
     }
 
+    pub async fn get_repository(&self, repository_id: i64) -> Result<Repository> {
+        let url = format!("https://api.github.com/repositories/{}", repository_id);
+        let response = self.client.get(&url).send().await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "Failed to get repository {}: {}",
+                repository_id,
+                response.status()
+            );
+        }
+
+        let github_repo: GitHubRepo = response.json().await?;
+
+        Ok(Repository {
+            id: github_repo.id,
+            name: github_repo.name,
+            full_name: github_repo.full_name,
+            owner: github_repo.owner.login,
+            default_branch: github_repo.default_branch,
+        })
+    }
+
+    // The commit the PR currently points at, which may live in a fork
+    pub async fn get_pull_request_head_sha(&self, repo: &str, pr_number: i32) -> Result<String> {
+        let url = format!("https://api.github.com/repos/{}/pulls/{}", repo, pr_number);
+        let response = self.client.get(&url).send().await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to get PR: {}", response.status());
+        }
+
+        let pr: serde_json::Value = response.json().await?;
+        let sha = pr["head"]["sha"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("No head SHA found for PR #{}", pr_number))?;
+
+        Ok(sha.to_string())
+    }
+
+    // Merges exactly `head_sha`, never the tip of the head branch, which may have moved
     pub async fn create_try_branch(
         &self,
         repo: &str,
-        head_branch: &str,
+        head_sha: &str,
         base_branch: &str,
         try_branch: &str,
     ) -> Result<()> {
         // Get base branch SHA
         let base_sha = self.get_branch_sha(repo, base_branch).await?;
 
-        // Get head branch SHA
-        let head_sha = self.get_branch_sha(repo, head_branch).await?;
-
         // Delete existing try branch if it exists
         let _ = self.delete_branch(repo, try_branch).await;
 
@@ -115,12 +203,18 @@ impl GitHubClient {
         self.create_branch(repo, try_branch, &base_sha).await?;
 
         // Merge head into try branch
-        self.merge_branch(repo, try_branch, &head_sha).await?;
+        self.merge_branch(
+            repo,
+            try_branch,
+            head_sha,
+            &format!("Try merge into {}", try_branch),
+        )
+        .await?;
 
         Ok(())
     }
 
-    async fn get_branch_sha(&self, repo: &str, branch: &str) -> Result<String> {
+    pub async fn get_branch_sha(&self, repo: &str, branch: &str) -> Result<String> {
         let url = format!("https://api.github.com/repos/{}/branches/{}", repo, branch);
         let response = self.client.get(&url).send().await?;
 
@@ -152,12 +246,18 @@ impl GitHubClient {
         Ok(())
     }
 
-    async fn merge_branch(&self, repo: &str, target_branch: &str, source_sha: &str) -> Result<()> {
+    pub async fn merge_branch(
+        &self,
+        repo: &str,
+        target_branch: &str,
+        source_sha: &str,
+        message: &str,
+    ) -> Result<()> {
         let url = format!("https://api.github.com/repos/{}/merges", repo);
         let payload = json!({
             "base": target_branch,
             "head": source_sha,
-            "commit_message": format!("Try merge into {}", target_branch)
+            "commit_message": message
         });
 
         let response = self.client.post(&url).json(&payload).send().await?;
@@ -173,7 +273,43 @@ impl GitHubClient {
         Ok(())
     }
 
-    async fn delete_branch(&self, repo: &str, branch: &str) -> Result<()> {
+    pub async fn reset_branch(&self, repo: &str, branch: &str, sha: &str) -> Result<()> {
+        // Delete existing branch if it exists
+        let _ = self.delete_branch(repo, branch).await;
+
+        self.create_branch(repo, branch, sha).await
+    }
+
+    // The error holds GitHub's explanation of a refusal, e.g. that the update is not a
+    // fast-forward or that the branch is protected
+    pub async fn fast_forward_branch(&self, repo: &str, branch: &str, sha: &str) -> Result<()> {
+        let url = format!(
+            "https://api.github.com/repos/{}/git/refs/heads/{}",
+            repo, branch
+        );
+        let payload = json!({
+            "sha": sha,
+            "force": false
+        });
+
+        let response = self.client.patch(&url).json(&payload).send().await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body: serde_json::Value = response.json().await.unwrap_or_default();
+            anyhow::bail!(
+                "Failed to fast-forward {} to {}: {} ({})",
+                branch,
+                sha,
+                status,
+                body["message"].as_str().unwrap_or("no details")
+            );
+        }
+
+        Ok(())
+    }
+
+    pub async fn delete_branch(&self, repo: &str, branch: &str) -> Result<()> {
         let url = format!(
             "https://api.github.com/repos/{}/git/refs/heads/{}",
             repo, branch
@@ -181,11 +317,109 @@ impl GitHubClient {
         let response = self.client.delete(&url).send().await?;
 
         // Don't error if branch doesn't exist
+        if !response.status().is_success()
+            && response.status() != reqwest::StatusCode::NOT_FOUND
+            && response.status() != reqwest::StatusCode::UNPROCESSABLE_ENTITY
+        {
+            anyhow::bail!("Failed to delete branch {}: {}", branch, response.status());
+        }
+
         Ok(())
     }
 
-    pub async fn get_branch_status(&self, repo: &str, branch: &str) -> Result<String> {
-        let sha = self.get_branch_sha(repo, branch).await?;
+    // Cancels the workflow runs of the branch that have not finished yet and returns how
+    // many were cancelled
+    pub async fn cancel_workflow_runs(&self, repo: &str, branch: &str) -> Result<usize> {
+        let url = format!("https://api.github.com/repos/{}/actions/runs", repo);
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("branch", branch), ("per_page", "100")])
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "Failed to list workflow runs of {}: {}",
+                branch,
+                response.status()
+            );
+        }
+
+        let runs: serde_json::Value = response.json().await?;
+        let mut cancelled = 0;
+        for run in runs["workflow_runs"].as_array().into_iter().flatten() {
+            if run["status"].as_str() == Some("completed") {
+                continue;
+            }
+            let Some(run_id) = run["id"].as_i64() else {
+                continue;
+            };
+
+            let url = format!(
+                "https://api.github.com/repos/{}/actions/runs/{}/cancel",
+                repo, run_id
+            );
+            let response = self.client.post(&url).send().await?;
+
+            // 409 means the run finished in the meantime
+            if response.status().is_success() {
+                cancelled += 1;
+            } else if response.status() != reqwest::StatusCode::CONFLICT {
+                anyhow::bail!(
+                    "Failed to cancel workflow run {}: {}",
+                    run_id,
+                    response.status()
+                );
+            }
+        }
+
+        Ok(cancelled)
+    }
+
+    // The patch of every file changed between the merge base of `base` and `head`, and
+    // `head`. Files without a patch (binary or too large) get their blob SHA instead.
+    pub async fn get_changes(
+        &self,
+        repo: &str,
+        base: &str,
+        head: &str,
+    ) -> Result<Vec<(String, String)>> {
+        let url = format!(
+            "https://api.github.com/repos/{}/compare/{}...{}",
+            repo, base, head
+        );
+        let response = self.client.get(&url).send().await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "Failed to compare {}...{}: {}",
+                base,
+                head,
+                response.status()
+            );
+        }
+
+        let comparison: serde_json::Value = response.json().await?;
+        let changes = comparison["files"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|file| {
+                let filename = file["filename"].as_str().unwrap_or("").to_string();
+                let change = file["patch"]
+                    .as_str()
+                    .or_else(|| file["sha"].as_str())
+                    .unwrap_or("")
+                    .to_string();
+                (filename, change)
+            })
+            .collect();
+
+        Ok(changes)
+    }
+
+    pub async fn get_commit_statuses(&self, repo: &str, sha: &str) -> Result<Vec<CommitStatus>> {
         let url = format!(
             "https://api.github.com/repos/{}/commits/{}/status",
             repo, sha
@@ -194,13 +428,239 @@ impl GitHubClient {
         let response = self.client.get(&url).send().await?;
 
         if !response.status().is_success() {
-            return Ok("unknown".to_string());
+            anyhow::bail!("Failed to get statuses for {}: {}", sha, response.status());
+        }
+
+        let combined: CombinedStatus = response.json().await?;
+
+        Ok(combined.statuses)
+    }
+
+    pub async fn get_check_runs(&self, repo: &str, sha: &str) -> Result<Vec<CheckRun>> {
+        let url = format!(
+            "https://api.github.com/repos/{}/commits/{}/check-runs?per_page=100",
+            repo, sha
+        );
+
+        let response = self.client.get(&url).send().await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "Failed to get check runs for {}: {}",
+                sha,
+                response.status()
+            );
+        }
+
+        let list: CheckRunList = response.json().await?;
+
+        Ok(list.check_runs)
+    }
+
+    pub async fn get_check_suites(&self, repo: &str, sha: &str) -> Result<Vec<CheckSuite>> {
+        let url = format!(
+            "https://api.github.com/repos/{}/commits/{}/check-suites?per_page=100",
+            repo, sha
+        );
+
+        let response = self.client.get(&url).send().await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "Failed to get check suites for {}: {}",
+                sha,
+                response.status()
+            );
+        }
+
+        let list: CheckSuiteList = response.json().await?;
+
+        Ok(list.check_suites)
+    }
+
+    // Returns the user's role on the repository (admin, maintain, write, triage or read),
+    // or None when they are not a collaborator
+    pub async fn get_collaborator_permission(
+        &self,
+        repo: &str,
+        user: &str,
+    ) -> Result<Option<String>> {
+        let url = format!(
+            "https://api.github.com/repos/{}/collaborators/{}/permission",
+            repo, user
+        );
+        let response = self.client.get(&url).send().await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "Failed to get permission of {} on {}: {}",
+                user,
+                repo,
+                response.status()
+            );
+        }
+
+        let permission: serde_json::Value = response.json().await?;
+        let role = permission["role_name"]
+            .as_str()
+            .or_else(|| permission["permission"].as_str())
+            .map(|role| role.to_string());
+
+        Ok(role)
+    }
+
+    // Returns None when the file does not exist on that ref
+    pub async fn get_file_contents(
+        &self,
+        repo: &str,
+        path: &str,
+        git_ref: &str,
+    ) -> Result<Option<String>> {
+        let url = format!(
+            "https://api.github.com/repos/{}/contents/{}?ref={}",
+            repo, path, git_ref
+        );
+        let response = self.client.get(&url).send().await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to get {}: {}", path, response.status());
+        }
+
+        let file: serde_json::Value = response.json().await?;
+        let encoded: String = file["content"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("No content found for {}", path))?
+            .split_whitespace()
+            .collect();
+        let decoded = base64::engine::general_purpose::STANDARD.decode(encoded)?;
+
+        Ok(Some(String::from_utf8(decoded)?))
+    }
+
+    // With `head_sha`, GitHub refuses the merge if the PR head is no longer that commit
+    pub async fn merge_pull_request(
+        &self,
+        repo: &str,
+        pr_number: i32,
+        merge_method: &str,
+        head_sha: Option<&str>,
+    ) -> Result<String> {
+        let url = format!(
+            "https://api.github.com/repos/{}/pulls/{}/merge",
+            repo, pr_number
+        );
+        let mut payload = json!({
+            "merge_method": merge_method
+        });
+        if let Some(head_sha) = head_sha {
+            payload["sha"] = json!(head_sha);
+        }
+
+        let response = self.client.put(&url).json(&payload).send().await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to merge PR #{}: {}", pr_number, response.status());
+        }
+
+        let result: serde_json::Value = response.json().await?;
+        let sha = result["sha"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("No SHA found for merge of PR #{}", pr_number))?;
+
+        Ok(sha.to_string())
+    }
+
+    // Check runs can only be created with a GitHub App installation token
+    pub async fn create_check_run(&self, repo: &str, name: &str, head_sha: &str) -> Result<i64> {
+        let url = format!("https://api.github.com/repos/{}/check-runs", repo);
+        let payload = json!({
+            "name": name,
+            "head_sha": head_sha,
+            "status": "queued"
+        });
+
+        let response = self.client.post(&url).json(&payload).send().await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to create check run {}: {}", name, response.status());
         }
 
-        let status: serde_json::Value = response.json().await?;
-        let state = status["state"].as_str().unwrap_or("pending");
+        let check_run: serde_json::Value = response.json().await?;
+        check_run["id"]
+            .as_i64()
+            .ok_or_else(|| anyhow::anyhow!("No id found for check run {}", name))
+    }
 
-        Ok(state.to_string())
+    // status is "queued", "in_progress" or "completed"; completed runs need a conclusion
+    // such as "success", "failure" or "cancelled"
+    pub async fn update_check_run(
+        &self,
+        repo: &str,
+        check_run_id: i64,
+        status: &str,
+        conclusion: Option<&str>,
+        output: Option<&CheckRunOutput>,
+    ) -> Result<()> {
+        let url = format!(
+            "https://api.github.com/repos/{}/check-runs/{}",
+            repo, check_run_id
+        );
+        let mut payload = json!({
+            "status": status
+        });
+        if status == "in_progress" {
+            payload["started_at"] = json!(chrono::Utc::now());
+        }
+        if let Some(conclusion) = conclusion {
+            payload["conclusion"] = json!(conclusion);
+            payload["completed_at"] = json!(chrono::Utc::now());
+        }
+        if let Some(output) = output {
+            payload["output"] = json!(output);
+        }
+
+        let response = self.client.patch(&url).json(&payload).send().await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "Failed to update check run {}: {}",
+                check_run_id,
+                response.status()
+            );
+        }
+
+        Ok(())
+    }
+
+    // content is one of GitHub's reaction names, e.g. "eyes", "rocket", "+1" or "-1"
+    pub async fn add_reaction(&self, repo: &str, comment_id: i64, content: &str) -> Result<()> {
+        let url = format!(
+            "https://api.github.com/repos/{}/issues/comments/{}/reactions",
+            repo, comment_id
+        );
+        let payload = json!({
+            "content": content
+        });
+
+        let response = self.client.post(&url).json(&payload).send().await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "Failed to react to comment {}: {}",
+                comment_id,
+                response.status()
+            );
+        }
+
+        Ok(())
     }
 
     pub async fn comment_on_pr(&self, repo: &str, pr_number: i32, comment: &str) -> Result<()> {
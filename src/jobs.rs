@@ -0,0 +1,440 @@
+// jobs.rs
+use anyhow::Result;
+use github_merge_bot::{Repository, TryMergeJob};
+use std::{fmt, sync::Arc};
+use tokio::{
+    sync::Notify,
+    time::{sleep, Duration},
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    ci::{check_run_output, update_check_run},
+    queue, react, run_try_merge, AppState,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub const ALL: &'static [JobStatus] = &[
+        JobStatus::Pending,
+        JobStatus::Running,
+        JobStatus::Completed,
+        JobStatus::Failed,
+        JobStatus::Cancelled,
+    ];
+
+    // Jobs that still have work to do, and can be cancelled
+    pub const ACTIVE: &'static [JobStatus] = &[JobStatus::Pending, JobStatus::Running];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(JobStatus::Pending),
+            "running" => Some(JobStatus::Running),
+            "completed" => Some(JobStatus::Completed),
+            "failed" => Some(JobStatus::Failed),
+            "cancelled" => Some(JobStatus::Cancelled),
+            _ => None,
+        }
+    }
+
+    // The transitions a job may make. Running jobs go back to pending when their worker
+    // stops, finished and cancelled jobs never change again.
+    pub fn can_transition_to(&self, next: JobStatus) -> bool {
+        use JobStatus::*;
+
+        matches!(
+            (self, next),
+            (Pending, Running)
+                | (Pending, Cancelled)
+                | (Running, Pending)
+                | (Running, Completed)
+                | (Running, Failed)
+                | (Running, Cancelled)
+        )
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// A status change refused by the transition table, or because the job was no longer in
+// the expected status when it was applied (e.g. it was cancelled meanwhile)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransitionError {
+    pub job_id: Uuid,
+    pub from: JobStatus,
+    pub to: JobStatus,
+    // What the job turned out to be in; None when the transition itself is not allowed
+    // or the job does not exist
+    pub actual: Option<JobStatus>,
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "try job {} cannot move from {} to {}",
+            self.job_id, self.from, self.to
+        )?;
+        match self.actual {
+            Some(actual) if actual == self.from => write!(f, ", its lease was lost"),
+            Some(actual) => write!(f, ", it is {}", actual),
+            None if !self.from.can_transition_to(self.to) => write!(f, ", which is not allowed"),
+            None => write!(f, ", it does not exist"),
+        }
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+// Applies a status change through `Database::transition_try_merge_job`, only while the
+// job is still leased to `lease_owner` when one is given. Returns false, logging why,
+// when it was rejected.
+pub async fn transition(
+    state: &AppState,
+    job_id: Uuid,
+    from: JobStatus,
+    to: JobStatus,
+    lease_owner: Option<&str>,
+    error_message: Option<&str>,
+) -> Result<bool> {
+    match state
+        .db
+        .transition_try_merge_job(job_id, from, to, lease_owner, error_message)
+        .await
+    {
+        Ok(()) => Ok(true),
+        Err(e) => match e.downcast_ref::<TransitionError>() {
+            Some(rejected) => {
+                warn!("Rejected status change: {}", rejected);
+                Ok(false)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+// A try job claimed by this instance, with the bookkeeping the shared job type has no room for
+#[derive(Debug, Clone)]
+pub struct LeasedJob {
+    pub job: TryMergeJob,
+    pub comment_id: Option<i64>,
+    pub check_run_id: Option<i64>,
+    // The PR head commit the try was requested for, None for jobs queued before pinning
+    pub head_sha: Option<String>,
+    pub attempts: i32,
+    // The worker holding the lease, `<instance id>/<worker>`
+    pub lease_owner: String,
+}
+
+// A try job as stored, with the columns the shared job type has no room for
+#[derive(Debug, Clone)]
+pub struct JobRecord {
+    pub job: TryMergeJob,
+    pub head_sha: Option<String>,
+    pub attempts: i32,
+    pub lease_owner: Option<String>,
+}
+
+// Wakes idle workers up when a job is enqueued. Workers also poll the database, which
+// picks up jobs enqueued by other instances and jobs whose lease expired.
+#[derive(Debug, Clone, Default)]
+pub struct JobQueue {
+    wakeup: Arc<Notify>,
+}
+
+impl JobQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn notify(&self) {
+        self.wakeup.notify_one();
+    }
+}
+
+// Requeues the jobs a previous run of this instance left behind, resumes the merge queues
+// with work left and starts the workers
+pub async fn start(state: Arc<AppState>) -> Result<()> {
+    let recovered = state
+        .db
+        .recover_try_merge_jobs(&state.config.instance_id)
+        .await?;
+    if recovered > 0 {
+        info!("Requeued {} try jobs interrupted by a restart", recovered);
+    }
+
+    let released = state
+        .db
+        .release_instance_locks(&state.config.instance_id)
+        .await?;
+    if released > 0 {
+        info!("Released {} locks held before a restart", released);
+    }
+
+    // Entries left testing are requeued by whoever takes the merge queue lock
+    for repository_id in state.db.get_queue_repository_ids().await? {
+        let state = state.clone();
+        tokio::spawn(async move {
+            match state.github.get_repository(repository_id).await {
+                Ok(repo) => queue::spawn_processing(state, repo),
+                Err(e) => error!(
+                    "Could not resume the merge queue of repository {}: {}",
+                    repository_id, e
+                ),
+            }
+        });
+    }
+
+    for worker in 0..state.config.job_workers.max(1) {
+        let state = state.clone();
+        tokio::spawn(async move { run_worker(&state, worker).await });
+    }
+
+    Ok(())
+}
+
+async fn run_worker(state: &AppState, worker: usize) {
+    let idle = Duration::from_secs(state.config.job_lease_secs.max(1));
+    // Each worker holds its own leases, so that a job taken over by another worker of
+    // the same instance is not renewed or finished by the one that lost it
+    let owner = format!("{}/{}", state.config.instance_id, worker);
+
+    loop {
+        match state
+            .db
+            .lease_try_merge_job(&owner, state.config.job_lease_secs)
+            .await
+        {
+            Ok(Some(leased)) => run_job(state, leased).await,
+            Ok(None) => {
+                tokio::select! {
+                    _ = state.jobs.wakeup.notified() => {}
+                    _ = sleep(idle) => {}
+                }
+            }
+            Err(e) => {
+                error!("Worker {} could not lease a job: {}", worker, e);
+                sleep(idle).await;
+            }
+        }
+    }
+}
+
+// Errors leave the job running under an expiring lease, so it is retried by whichever
+// worker claims it next until it runs out of attempts
+async fn run_job(state: &AppState, leased: LeasedJob) {
+    let job = &leased.job;
+    info!(
+        "Running try job {} for PR #{} (attempt {})",
+        job.id, job.pr_number, leased.attempts
+    );
+
+    let result = async {
+        let repo = state.github.get_repository(job.repository_id).await?;
+        if leased.attempts > state.config.job_max_attempts {
+            return give_up(state, &repo, &leased).await;
+        }
+
+        tokio::select! {
+            result = run_try_merge(state, &repo, &leased) => result,
+            _ = heartbeat(state, job.id, &leased.lease_owner) => {
+                warn!("Lost the lease on try job {} (cancelled or taken over), abandoning it", job.id);
+                Ok(())
+            }
+        }
+    }
+    .await;
+
+    if let Err(e) = result {
+        error!("Try job {} failed, it will be retried: {}", job.id, e);
+    }
+}
+
+// Renews the lease while the job runs and returns once it has been lost
+async fn heartbeat(state: &AppState, id: Uuid, owner: &str) {
+    let interval = Duration::from_secs((state.config.job_lease_secs / 3).max(1));
+
+    loop {
+        sleep(interval).await;
+        match state
+            .db
+            .renew_try_merge_job_lease(id, owner, state.config.job_lease_secs)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => warn!("Could not renew the lease on try job {}: {}", id, e),
+        }
+    }
+}
+
+// Cancels the PR's try jobs along with their workflow runs and branches, recording the
+// reason on the jobs. Returns the branches of the cancelled jobs and how many workflow
+// runs were cancelled.
+pub async fn cancel(
+    state: &AppState,
+    repo: &Repository,
+    pr_number: i32,
+    reason: &str,
+) -> Result<(Vec<String>, usize)> {
+    let mut branches = Vec::new();
+    let mut runs = 0;
+
+    for (job, check_run_id) in state
+        .db
+        .cancel_try_merge_jobs(repo.id, pr_number, reason)
+        .await?
+    {
+        info!(
+            "Cancelled try job {} for {}#{}",
+            job.id, repo.full_name, pr_number
+        );
+
+        match state
+            .github
+            .cancel_workflow_runs(&repo.full_name, &job.branch_name)
+            .await
+        {
+            Ok(cancelled) => runs += cancelled,
+            Err(e) => warn!(
+                "Could not cancel workflow runs of {}: {}",
+                job.branch_name, e
+            ),
+        }
+        if let Err(e) = state
+            .github
+            .delete_branch(&repo.full_name, &job.branch_name)
+            .await
+        {
+            warn!("Could not delete {}: {}", job.branch_name, e);
+        }
+        update_check_run(
+            state,
+            repo,
+            check_run_id,
+            "completed",
+            Some("cancelled"),
+            Some(&check_run_output("Try build cancelled", reason, None)),
+        )
+        .await;
+
+        branches.push(job.branch_name);
+    }
+
+    Ok((branches, runs))
+}
+
+async fn give_up(state: &AppState, repo: &Repository, leased: &LeasedJob) -> Result<()> {
+    let job = &leased.job;
+    let message = format!(
+        "Try build abandoned after {} attempts",
+        state.config.job_max_attempts
+    );
+    warn!("{} for {}#{}", message, repo.full_name, job.pr_number);
+
+    if !transition(
+        state,
+        job.id,
+        JobStatus::Running,
+        JobStatus::Failed,
+        Some(&leased.lease_owner),
+        Some(&message),
+    )
+    .await?
+    {
+        return Ok(());
+    }
+
+    if let Some(comment_id) = leased.comment_id {
+        react(state, repo, comment_id, "-1").await;
+    }
+    update_check_run(
+        state,
+        repo,
+        leased.check_run_id,
+        "completed",
+        Some("failure"),
+        Some(&check_run_output("Try build failed", &message, None)),
+    )
+    .await;
+    state
+        .github
+        .comment_on_pr(
+            &repo.full_name,
+            job.pr_number,
+            &format!(":broken_heart: {}", message),
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_only_listed_transitions() {
+        use JobStatus::*;
+
+        let allowed = [
+            (Pending, Running),
+            (Pending, Cancelled),
+            (Running, Pending),
+            (Running, Completed),
+            (Running, Failed),
+            (Running, Cancelled),
+        ];
+        for from in JobStatus::ALL {
+            for to in JobStatus::ALL {
+                assert_eq!(
+                    from.can_transition_to(*to),
+                    allowed.contains(&(*from, *to)),
+                    "{} -> {}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn finished_jobs_never_change() {
+        for from in [
+            JobStatus::Completed,
+            JobStatus::Failed,
+            JobStatus::Cancelled,
+        ] {
+            assert!(JobStatus::ALL.iter().all(|to| !from.can_transition_to(*to)));
+        }
+    }
+
+    #[test]
+    fn parses_its_own_names() {
+        for status in JobStatus::ALL {
+            assert_eq!(JobStatus::parse(status.as_str()), Some(*status));
+        }
+        assert_eq!(JobStatus::parse("done"), None);
+    }
+}
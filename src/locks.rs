@@ -0,0 +1,148 @@
+// locks.rs
+use anyhow::Result;
+use github_merge_bot::Repository;
+use tokio::{
+    task::JoinHandle,
+    time::{sleep, Duration},
+};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{database::Database, AppState};
+
+// Lock serializing merge queue processing, and with it every push to the staging branch
+pub const MERGE_QUEUE_LOCK: &str = "merge-queue";
+
+// A lock on one of a repository's resources, shared by every replica through the
+// database. The lease is renewed in the background while the lock is held; if it
+// still expires (e.g. the database was unreachable) another replica can take the
+// lock over with a higher fencing token. Callers check the lock with `ensure_held`
+// right before each write it protects, so a holder that lost it stops writing.
+#[derive(Debug)]
+pub struct RepoLock {
+    db: Database,
+    repository_id: i64,
+    resource: String,
+    owner: String,
+    fencing_token: i64,
+    lease_secs: u64,
+    renewal: JoinHandle<()>,
+}
+
+impl RepoLock {
+    // Returns None while another task or replica holds the lock
+    pub async fn try_acquire(
+        state: &AppState,
+        repo: &Repository,
+        resource: &str,
+    ) -> Result<Option<Self>> {
+        let owner = format!("{}/{}", state.config.instance_id, Uuid::new_v4());
+        let lease_secs = state.config.lock_lease_secs;
+
+        let Some(fencing_token) = state
+            .db
+            .acquire_lock(repo.id, resource, &owner, lease_secs)
+            .await?
+        else {
+            return Ok(None);
+        };
+        debug!(
+            "Locked {} of {} with fencing token {}",
+            resource, repo.full_name, fencing_token
+        );
+
+        let renewal = tokio::spawn(keep_alive(
+            state.db.clone(),
+            repo.id,
+            resource.to_string(),
+            owner.clone(),
+            fencing_token,
+            lease_secs,
+        ));
+
+        Ok(Some(Self {
+            db: state.db.clone(),
+            repository_id: repo.id,
+            resource: resource.to_string(),
+            owner,
+            fencing_token,
+            lease_secs,
+            renewal,
+        }))
+    }
+
+    pub fn fencing_token(&self) -> i64 {
+        self.fencing_token
+    }
+
+    pub async fn ensure_held(&self) -> Result<()> {
+        let held = self
+            .db
+            .renew_lock(
+                self.repository_id,
+                &self.resource,
+                &self.owner,
+                self.fencing_token,
+                self.lease_secs,
+            )
+            .await?;
+
+        if !held {
+            anyhow::bail!(
+                "Lost the lock on {} (fencing token {}) to another holder",
+                self.resource,
+                self.fencing_token
+            );
+        }
+
+        Ok(())
+    }
+
+    pub async fn release(self) -> Result<()> {
+        self.renewal.abort();
+        self.db
+            .release_lock(
+                self.repository_id,
+                &self.resource,
+                &self.owner,
+                self.fencing_token,
+            )
+            .await
+    }
+}
+
+// A lock dropped without being released stays taken until its lease runs out
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        self.renewal.abort();
+    }
+}
+
+async fn keep_alive(
+    db: Database,
+    repository_id: i64,
+    resource: String,
+    owner: String,
+    fencing_token: i64,
+    lease_secs: u64,
+) {
+    let interval = Duration::from_secs((lease_secs / 3).max(1));
+
+    loop {
+        sleep(interval).await;
+        match db
+            .renew_lock(repository_id, &resource, &owner, fencing_token, lease_secs)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!(
+                    "Lock on {} (fencing token {}) was taken over",
+                    resource, fencing_token
+                );
+                return;
+            }
+            Err(e) => warn!("Could not renew the lock on {}: {}", resource, e),
+        }
+    }
+}
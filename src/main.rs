@@ -1,24 +1,45 @@
 use anyhow::Result;
 use axum::{extract::State, http::StatusCode, response::Json, routing::post, Router};
 use chrono::Utc;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{Mutex, RwLock};
+use std::sync::Arc;
 use tracing::{error, info, warn};
 
+mod api;
+mod audit;
+mod auth;
+mod ci;
 mod commands;
 mod config;
 mod database;
 mod github;
+mod jobs;
+mod locks;
+mod migrations;
+mod queue;
+mod repo_config;
+mod repositories;
 mod webhook;
+mod workers;
 
-use commands::CommandProcessor;
+use audit::{AuditEvent, EventKind};
+use auth::PermissionCache;
+use ci::{
+    check_run_output, create_check_run, update_check_run, wait_for_ci, CiReport, CiState,
+    CiWatcher, TRY_CHECK_NAME,
+};
+use commands::{Command, CommandError, CommandProcessor, COMMANDS};
 use config::Config;
 use database::Database;
 use github::GitHubClient;
+use jobs::{JobQueue, JobStatus, LeasedJob};
+use locks::RepoLock;
+use repo_config::{InvalidConfig, RepoConfigCache, REPO_CONFIG_PATH};
+use repositories::KnownRepositories;
 use webhook::WebhookHandler;
+use workers::{CommentCommands, PrPush, RepoTask, RepoWorkers};
 
 // Import types from lib
-use github_merge_bot::{PullRequest, Repository, TryMergeJob};
+use github_merge_bot::{Repository, TryMergeJob};
 
 #[derive(Debug, Clone)]
 pub struct AppState {
@@ -26,20 +47,27 @@ pub struct AppState {
     pub db: Database,
     pub github: GitHubClient,
     pub webhook_handler: WebhookHandler,
-    pub command_processor: Arc<Mutex<CommandProcessor>>,
-    pub active_jobs: Arc<RwLock<HashMap<String, TryMergeJob>>>,
+    pub command_processor: Arc<CommandProcessor>,
+    pub ci_watcher: CiWatcher,
+    pub repo_configs: RepoConfigCache,
+    pub permissions: PermissionCache,
+    pub jobs: JobQueue,
+    pub repo_workers: RepoWorkers,
+    pub known_repositories: KnownRepositories,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::init();
+    tracing_subscriber::fmt::init();
 
     let config = Config::load()?;
     let db = Database::new(&config.database_url).await?;
     let github = GitHubClient::new(&config.github_token);
     let webhook_handler = WebhookHandler::new(&config.webhook_secret);
-    let command_processor = Arc::new(Mutex::new(CommandProcessor::new()));
-    let active_jobs = Arc::new(RwLock::new(HashMap::new()));
+    let command_processor = Arc::new(CommandProcessor::new(&config.bot_name, &config.bot_aliases));
+    let ci_watcher = CiWatcher::new();
+    let repo_configs = RepoConfigCache::new(config.repo_config_cache_ttl_secs);
+    let permissions = PermissionCache::new(config.permission_cache_ttl_secs);
 
     // Initialize database
     db.migrate().await?;
@@ -50,13 +78,27 @@ async fn main() -> Result<()> {
         github,
         webhook_handler,
         command_processor,
-        active_jobs,
+        ci_watcher,
+        repo_configs,
+        permissions,
+        jobs: JobQueue::new(),
+        repo_workers: RepoWorkers::new(config.repo_queue_capacity),
+        known_repositories: KnownRepositories::new(),
     };
+    let state = Arc::new(state);
+
+    // Resume interrupted try jobs and merge queues, and start the workers
+    jobs::start(state.clone()).await?;
+
+    if config.api_token.is_none() {
+        warn!("API_TOKEN is not set, the /api endpoints are disabled");
+    }
 
     let app = Router::new()
         .route("/webhook", post(handle_webhook))
         .route("/health", axum::routing::get(health_check))
-        .with_state(Arc::new(state))
+        .merge(api::router(state.clone()))
+        .with_state(state)
         .layer(tower_http::trace::TraceLayer::new_for_http());
 
     let listener = tokio::net::TcpListener::bind(&config.bind_address).await?;
@@ -72,9 +114,11 @@ async fn handle_webhook(
     headers: axum::http::HeaderMap,
     body: String,
 ) -> Result<StatusCode, StatusCode> {
+    // Owned, since the event is processed after the request has been answered
     let event_type = headers
         .get("X-GitHub-Event")
         .and_then(|h| h.to_str().ok())
+        .map(str::to_string)
         .ok_or(StatusCode::BAD_REQUEST)?;
 
     if !state
@@ -90,7 +134,7 @@ async fn handle_webhook(
         serde_json::from_str(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
 
     tokio::spawn(async move {
-        if let Err(e) = process_webhook_event(&state, event_type, payload).await {
+        if let Err(e) = process_webhook_event(&state, &event_type, payload).await {
             error!("Error processing webhook: {}", e);
         }
     });
@@ -99,49 +143,162 @@ async fn handle_webhook(
 }
 
 async fn process_webhook_event(
-    state: &AppState,
+    state: &Arc<AppState>,
     event_type: &str,
     payload: serde_json::Value,
 ) -> Result<()> {
+    // Jobs and queue entries reference the repository, so it is recorded first
+    repositories::sync_from_webhook(state, event_type, &payload).await?;
+
     match event_type {
         "issue_comment" => {
+            // Editing or deleting an old comment must not run its commands again, and
+            // the bot's own replies quote commands
+            let author = payload["comment"]["user"]["login"].as_str().unwrap_or("");
+            if payload["action"].as_str() != Some("created") || is_bot(&state.config, author) {
+                return Ok(());
+            }
+            // GitHub sends the same event for comments on plain issues, which have no
+            // branch to try or merge
+            if payload["issue"]["pull_request"].is_null() {
+                return Ok(());
+            }
+
             if let Some(comment_body) = payload["comment"]["body"].as_str() {
                 if let Some(pr_number) = payload["issue"]["number"].as_i64() {
-                    let repo = Repository {
-                        id: payload["repository"]["id"].as_i64().unwrap_or(0),
-                        name: payload["repository"]["name"]
-                            .as_str()
-                            .unwrap_or("")
-                            .to_string(),
-                        full_name: payload["repository"]["full_name"]
-                            .as_str()
-                            .unwrap_or("")
-                            .to_string(),
-                        owner: payload["repository"]["owner"]["login"]
-                            .as_str()
-                            .unwrap_or("")
-                            .to_string(),
-                        default_branch: payload["repository"]["default_branch"]
-                            .as_str()
-                            .unwrap_or("main")
-                            .to_string(),
+                    let repo = repository_from_payload(&payload);
+
+                    let commands = state.command_processor.parse_commands(comment_body);
+                    if commands.is_empty() {
+                        return Ok(());
+                    }
+
+                    let comment = CommentCommands {
+                        repo,
+                        pr_number: pr_number as i32,
+                        author: author.to_string(),
+                        comment_id: payload["comment"]["id"].as_i64().unwrap_or(0),
+                        commands,
                     };
 
-                    process_comment_command(state, &repo, pr_number as i32, comment_body).await?;
+                    // Commands run on the repository's worker; when its queue is full
+                    // the commenter is asked to retry instead of piling up work
+                    if let Err(RepoTask::Commands(comment)) = state
+                        .repo_workers
+                        .submit(state, RepoTask::Commands(comment))
+                        .await
+                    {
+                        warn!(
+                            "Command queue of {} is full, rejecting comment {}",
+                            comment.repo.full_name, comment.comment_id
+                        );
+                        react(state, &comment.repo, comment.comment_id, "confused").await;
+                        state
+                            .github
+                            .comment_on_pr(
+                                &comment.repo.full_name,
+                                comment.pr_number,
+                                ":hourglass: Too many commands are waiting on this repository, please try again later.",
+                            )
+                            .await?;
+                    }
                 }
             }
         }
         "pull_request" => {
             if let Some(action) = payload["action"].as_str() {
                 match action {
-                    "opened" | "synchronize" | "reopened" => {
-                        // Handle PR updates
+                    "opened" | "reopened" => {
                         info!("PR {} {}", payload["pull_request"]["number"], action);
                     }
+                    "synchronize" => {
+                        let push = PrPush {
+                            repo: repository_from_payload(&payload),
+                            pr_number: payload["pull_request"]["number"].as_i64().unwrap_or(0)
+                                as i32,
+                            base_branch: payload["pull_request"]["base"]["ref"]
+                                .as_str()
+                                .unwrap_or("")
+                                .to_string(),
+                            pusher: payload["sender"]["login"]
+                                .as_str()
+                                .unwrap_or("")
+                                .to_string(),
+                            before: payload["before"].as_str().unwrap_or("").to_string(),
+                            after: payload["after"].as_str().unwrap_or("").to_string(),
+                        };
+
+                        // Serialized with the repository's commands, so an approval is
+                        // never recorded against commits that were already replaced
+                        if let Err(RepoTask::Push(push)) =
+                            state.repo_workers.submit(state, RepoTask::Push(push)).await
+                        {
+                            error!(
+                                "Worker queue of {} is full, dropping push to PR {}",
+                                push.repo.full_name, push.pr_number
+                            );
+                        }
+                    }
+                    "closed" => {
+                        let repository_id = payload["repository"]["id"].as_i64().unwrap_or(0);
+                        if let Some(pr_number) = payload["pull_request"]["number"].as_i64() {
+                            let removed = state
+                                .db
+                                .remove_delegations(repository_id, pr_number as i32)
+                                .await?;
+                            if removed > 0 {
+                                info!("Cleared delegations of closed PR {}", pr_number);
+                            }
+                        }
+                    }
                     _ => {}
                 }
             }
         }
+        "push" => {
+            let full_name = payload["repository"]["full_name"].as_str().unwrap_or("");
+            let default_branch = payload["repository"]["default_branch"]
+                .as_str()
+                .unwrap_or("main");
+
+            if payload["ref"].as_str() == Some(&format!("refs/heads/{}", default_branch)) {
+                let touches_config = payload["commits"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|commit| {
+                        ["added", "modified", "removed"]
+                            .into_iter()
+                            .filter_map(move |key| commit[key].as_array())
+                            .flatten()
+                    })
+                    .any(|path| path.as_str() == Some(REPO_CONFIG_PATH));
+
+                if touches_config {
+                    state.repo_configs.invalidate(full_name).await;
+                }
+            }
+        }
+        "repository" => {
+            // The configuration is read from the default branch, which may have changed
+            if payload["action"].as_str() == Some("edited") {
+                if let Some(full_name) = payload["repository"]["full_name"].as_str() {
+                    state.repo_configs.invalidate(full_name).await;
+                }
+            }
+        }
+        "installation" | "installation_repositories" => {}
+        "status" | "check_suite" | "check_run" => {
+            let sha = match event_type {
+                "status" => payload["sha"].as_str(),
+                "check_suite" => payload["check_suite"]["head_sha"].as_str(),
+                _ => payload["check_run"]["head_sha"].as_str(),
+            };
+
+            if let Some(sha) = sha {
+                state.ci_watcher.notify(sha).await;
+            }
+        }
         _ => {
             info!("Unhandled webhook event: {}", event_type);
         }
@@ -150,26 +307,443 @@ async fn process_webhook_event(
     Ok(())
 }
 
-async fn process_comment_command(
-    state: &AppState,
+// Whether the login is the bot itself, under its name or one of its aliases. GitHub App
+// logins carry a `[bot]` suffix.
+fn is_bot(config: &Config, login: &str) -> bool {
+    let login = login.trim_end_matches("[bot]");
+    std::iter::once(&config.bot_name)
+        .chain(&config.bot_aliases)
+        .any(|name| name.trim_end_matches("[bot]").eq_ignore_ascii_case(login))
+}
+
+fn repository_from_payload(payload: &serde_json::Value) -> Repository {
+    Repository {
+        id: payload["repository"]["id"].as_i64().unwrap_or(0),
+        name: payload["repository"]["name"]
+            .as_str()
+            .unwrap_or("")
+            .to_string(),
+        full_name: payload["repository"]["full_name"]
+            .as_str()
+            .unwrap_or("")
+            .to_string(),
+        owner: payload["repository"]["owner"]["login"]
+            .as_str()
+            .unwrap_or("")
+            .to_string(),
+        default_branch: payload["repository"]["default_branch"]
+            .as_str()
+            .unwrap_or("main")
+            .to_string(),
+    }
+}
+
+// New commits on a PR invalidate its try builds and its approval, unless the repository
+// keeps approvals across pushes that leave the PR's changes as they were
+pub async fn process_pr_push(state: &AppState, push: &PrPush) -> Result<()> {
+    let (repo, pr_number) = (&push.repo, push.pr_number);
+    let short_sha = &push.after[..push.after.len().min(7)];
+    let reason = format!("New commits pushed ({})", short_sha);
+    audit::record(
+        state,
+        AuditEvent::new(EventKind::Push, repo.id, pr_number)
+            .actor(&push.pusher)
+            .sha(Some(&push.after))
+            .message(Some(&format!("Pushed {}..{}", push.before, push.after))),
+    )
+    .await;
+
+    // Webhooks may arrive out of order: a command run right after the push may already
+    // have pinned the new head, and must not be undone by the late push event. The queue
+    // entry and the try build are decided on separately, as either may be the one pinned.
+    let is_pinned = |head_sha: &Option<String>| head_sha.as_deref() == Some(push.after.as_str());
+    let mut entry = state.db.get_queue_entry(repo.id, pr_number).await?;
+    if entry
+        .as_ref()
+        .is_some_and(|entry| is_pinned(&entry.head_sha))
+    {
+        info!(
+            "The approval of {}#{} is already pinned to {}, keeping it",
+            repo.full_name, pr_number, push.after
+        );
+        entry = None;
+    }
+
+    let latest_job = state
+        .db
+        .list_try_merge_jobs(repo.id, None, Some(pr_number), 1, 0)
+        .await?
+        .into_iter()
+        .next()
+        .filter(|record| {
+            JobStatus::parse(&record.job.status)
+                .is_some_and(|status| JobStatus::ACTIVE.contains(&status))
+        });
+    let branches = if latest_job
+        .as_ref()
+        .is_some_and(|record| is_pinned(&record.head_sha))
+    {
+        info!(
+            "The try build of {}#{} is already pinned to {}, keeping it",
+            repo.full_name, pr_number, push.after
+        );
+        Vec::new()
+    } else {
+        jobs::cancel(state, repo, pr_number, &reason).await?.0
+    };
+
+    if branches.is_empty() && entry.is_none() {
+        return Ok(());
+    }
+
+    let mut lines = vec![format!(
+        ":warning: New commits were pushed to this PR ({}).",
+        short_sha
+    )];
+    if !branches.is_empty() {
+        lines.push("The try build of the previous commits was cancelled.".to_string());
+    }
+
+    if let Some(entry) = entry {
+        let repo_config = state.repo_configs.get(&state.github, repo).await?;
+        let keep = repo_config.keep_approval_on_rebase
+            && match same_changes(state, push).await {
+                Ok(same) => same,
+                Err(e) => {
+                    warn!(
+                        "Could not compare the changes of {}#{}: {}",
+                        repo.full_name, pr_number, e
+                    );
+                    false
+                }
+            };
+
+        if keep {
+            queue::requeue(state, repo, pr_number, &push.after).await?;
+            lines.push(format!(
+                "The changes are the same as before, so the approval by @{} is kept.",
+                entry.approved_by
+            ));
+        } else {
+            queue::cancel(state, repo, pr_number, &reason).await?;
+            lines.push(format!(
+                "The approval by @{} was dropped and the PR removed from the merge queue. Approve it again with `@{} r+`.",
+                entry.approved_by, state.config.bot_name
+            ));
+        }
+    }
+
+    info!(
+        "Invalidated {}#{} after a push of {}",
+        repo.full_name, pr_number, push.after
+    );
+    state
+        .github
+        .comment_on_pr(&repo.full_name, pr_number, &lines.join(" "))
+        .await
+}
+
+// Whether the PR changes the same files in the same way before and after the push.
+// Hunk headers are ignored since a rebase shifts line numbers.
+async fn same_changes(state: &AppState, push: &PrPush) -> Result<bool> {
+    let normalize = |changes: Vec<(String, String)>| {
+        let mut changes: Vec<(String, String)> = changes
+            .into_iter()
+            .map(|(filename, patch)| {
+                let patch: Vec<&str> = patch
+                    .lines()
+                    .filter(|line| !line.starts_with("@@"))
+                    .collect();
+                (filename, patch.join("\n"))
+            })
+            .collect();
+        changes.sort();
+        changes
+    };
+
+    let before = state
+        .github
+        .get_changes(&push.repo.full_name, &push.base_branch, &push.before)
+        .await?;
+    let after = state
+        .github
+        .get_changes(&push.repo.full_name, &push.base_branch, &push.after)
+        .await?;
+
+    Ok(normalize(before) == normalize(after))
+}
+
+// Runs the commands of one comment, called by the repository's worker
+pub async fn process_comment_command(
+    state: &Arc<AppState>,
     repo: &Repository,
     pr_number: i32,
-    comment_body: &str,
+    author: &str,
+    comment_id: i64,
+    commands: Vec<Result<Command, CommandError>>,
 ) -> Result<()> {
-    let processor = state.command_processor.lock().await;
+    react(state, repo, comment_id, "eyes").await;
+
+    // Commands are refused until an invalid configuration is fixed, rather than run
+    // with defaults the repository did not ask for
+    let repo_config = match state.repo_configs.get(&state.github, repo).await {
+        Ok(repo_config) => repo_config,
+        Err(e) => match e.downcast_ref::<InvalidConfig>() {
+            Some(invalid) => {
+                warn!(
+                    "Ignoring commands on {}#{}: {}",
+                    repo.full_name, pr_number, invalid
+                );
+                react(state, repo, comment_id, "confused").await;
+                return state
+                    .github
+                    .comment_on_pr(
+                        &repo.full_name,
+                        pr_number,
+                        &format!(
+                            ":warning: Commands are disabled until `{}` is fixed on `{}`: {}",
+                            REPO_CONFIG_PATH, repo.default_branch, invalid.reason
+                        ),
+                    )
+                    .await;
+            }
+            None => return Err(e),
+        },
+    };
+
+    for command in commands {
+        let command = match command {
+            Ok(command) => command,
+            Err(e) => {
+                warn!("Invalid command for PR {}: {}", pr_number, e);
+                let command = match &e {
+                    CommandError::Unknown(command) => command,
+                    CommandError::Malformed { command, .. } => command,
+                };
+                audit::record(
+                    state,
+                    AuditEvent::new(EventKind::Command, repo.id, pr_number)
+                        .actor(author)
+                        .command(command)
+                        .message(Some(&e.to_string())),
+                )
+                .await;
+                let bot_name = &state.config.bot_name;
+                let reply = match &e {
+                    CommandError::Unknown(command) => {
+                        let suggestion = commands::suggest(command)
+                            .map(|name| format!(" Did you mean `@{} {}`?", bot_name, name))
+                            .unwrap_or_default();
+                        format!(
+                            ":grey_question: Unknown command `{}`.{} Use `@{} help` to list the available commands.",
+                            command, suggestion, bot_name
+                        )
+                    }
+                    CommandError::Malformed { command, reason } => format!(
+                        ":grey_question: Could not run `{}`: {}. Usage: `{}`",
+                        command,
+                        reason,
+                        commands::usage(bot_name, command.split('=').next().unwrap_or(command))
+                            .unwrap_or_default()
+                    ),
+                };
+                react(state, repo, comment_id, "confused").await;
+                state
+                    .github
+                    .comment_on_pr(&repo.full_name, pr_number, &reply)
+                    .await?;
+                continue;
+            }
+        };
 
-    if let Some(command) = processor.parse_command(comment_body) {
         info!("Processing command: {:?} for PR {}", command, pr_number);
 
-        match command.as_str() {
-            "try" => {
-                execute_try_merge(state, repo, pr_number, "automation/bot/try").await?;
+        let refusal =
+            auth::check_permission(state, repo, &repo_config, pr_number, author, command.name())
+                .await?;
+        audit::record(
+            state,
+            AuditEvent::new(EventKind::Command, repo.id, pr_number)
+                .actor(author)
+                .command(&command)
+                .message(
+                    refusal
+                        .as_deref()
+                        .map(|reason| format!("Refused: {}", reason))
+                        .as_deref(),
+                ),
+        )
+        .await;
+
+        if let Some(reason) = refusal {
+            warn!(
+                "Refusing {} from {} on {}#{}: {}",
+                command.name(),
+                author,
+                repo.full_name,
+                pr_number,
+                reason
+            );
+            react(state, repo, comment_id, "-1").await;
+            state
+                .github
+                .comment_on_pr(&repo.full_name, pr_number, &format!(":lock: {}", reason))
+                .await?;
+            continue;
+        }
+
+        match command {
+            Command::Try(ref sha) | Command::TryMerge(ref sha) => {
+                let Some(head_sha) = pin_head_sha(state, repo, pr_number, sha.clone()).await?
+                else {
+                    react(state, repo, comment_id, "-1").await;
+                    continue;
+                };
+                let branch_prefix = if matches!(command, Command::Try(_)) {
+                    &repo_config.try_branch_prefix
+                } else {
+                    &repo_config.try_merge_branch_prefix
+                };
+                execute_try_merge(
+                    state,
+                    repo,
+                    pr_number,
+                    branch_prefix,
+                    &head_sha,
+                    author,
+                    comment_id,
+                )
+                .await?;
+            }
+            Command::Approve {
+                approver,
+                sha,
+                priority,
+                rollup,
+            } => {
+                let Some(head_sha) = pin_head_sha(state, repo, pr_number, sha).await? else {
+                    react(state, repo, comment_id, "-1").await;
+                    continue;
+                };
+                let approved_by = approver.as_deref().unwrap_or(author);
+                queue::approve(
+                    state,
+                    repo,
+                    pr_number,
+                    queue::Approval {
+                        approved_by,
+                        head_sha: &head_sha,
+                        priority,
+                        rollup,
+                        comment_id,
+                    },
+                )
+                .await?;
+
+                // The merge queue lock keeps landing to one task at a time
+                queue::spawn_processing(state.clone(), repo.clone());
+            }
+            Command::Delegate(delegate) => {
+                state
+                    .db
+                    .add_delegation(repo.id, pr_number, &delegate, author)
+                    .await?;
+                info!(
+                    "{} delegated {}#{} to {}",
+                    author, repo.full_name, pr_number, delegate
+                );
+                state
+                    .github
+                    .comment_on_pr(
+                        &repo.full_name,
+                        pr_number,
+                        &format!(
+                            ":v: @{} can now approve and try this PR with `@{} r+` and `@{} try`.",
+                            delegate, state.config.bot_name, state.config.bot_name
+                        ),
+                    )
+                    .await?;
+                react(state, repo, comment_id, "+1").await;
+            }
+            Command::Help => {
+                let mut lines = vec![format!("Commands available to @{} on this PR:\n", author)];
+                for (name, _, description) in COMMANDS {
+                    if auth::check_permission(state, repo, &repo_config, pr_number, author, name)
+                        .await?
+                        .is_none()
+                    {
+                        lines.push(format!(
+                            "- `{}` - {}",
+                            commands::usage(&state.config.bot_name, name).unwrap_or_default(),
+                            description
+                        ));
+                    }
+                }
+                state
+                    .github
+                    .comment_on_pr(&repo.full_name, pr_number, &lines.join("\n"))
+                    .await?;
+                react(state, repo, comment_id, "+1").await;
             }
-            "try-merge" => {
-                execute_try_merge(state, repo, pr_number, "automation/bot/try-merge").await?;
+            Command::Cancel => {
+                let reason = format!("Cancelled by @{}", author);
+                let (branches, try_runs) = jobs::cancel(state, repo, pr_number, &reason).await?;
+                let dequeued = queue::cancel(state, repo, pr_number, &reason).await?;
+
+                let mut lines = Vec::new();
+                if !branches.is_empty() {
+                    lines.push(format!(
+                        ":no_entry_sign: Cancelled the try build and deleted {}.",
+                        branches
+                            .iter()
+                            .map(|branch| format!("`{}`", branch))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ));
+                }
+                let mut runs = try_runs;
+                if let Some((status, merge_runs)) = dequeued {
+                    runs += merge_runs;
+                    lines.push(if status == queue::QueueStatus::Testing {
+                        ":no_entry_sign: Stopped testing the merge; the rest of its batch will be retested without this PR.".to_string()
+                    } else {
+                        ":no_entry_sign: Removed the PR from the merge queue.".to_string()
+                    });
+                }
+                if runs > 0 {
+                    lines.push(format!("Cancelled {} workflow run(s).", runs));
+                }
+
+                let message = if lines.is_empty() {
+                    ":information_source: Nothing to cancel on this PR.".to_string()
+                } else {
+                    lines.join("\n")
+                };
+                info!(
+                    "{} cancelled {}#{}: {}",
+                    author, repo.full_name, pr_number, message
+                );
+                state
+                    .github
+                    .comment_on_pr(&repo.full_name, pr_number, &message)
+                    .await?;
+                react(state, repo, comment_id, "+1").await;
             }
-            _ => {
-                warn!("Unknown command: {}", command);
+            Command::Undelegate => {
+                let removed = state.db.remove_delegations(repo.id, pr_number).await?;
+                info!(
+                    "{} revoked {} delegation(s) on {}#{}",
+                    author, removed, repo.full_name, pr_number
+                );
+                state
+                    .github
+                    .comment_on_pr(
+                        &repo.full_name,
+                        pr_number,
+                        ":v: Delegation revoked for this PR.",
+                    )
+                    .await?;
+                react(state, repo, comment_id, "+1").await;
             }
         }
     }
@@ -177,21 +751,60 @@ async fn process_comment_command(
     Ok(())
 }
 
+// The PR head commit a command applies to. A SHA given with the command has to be the
+// current head (or a prefix of it); otherwise the command is refused with a comment
+// and None is returned, since the PR changed after the commenter looked at it.
+async fn pin_head_sha(
+    state: &AppState,
+    repo: &Repository,
+    pr_number: i32,
+    requested: Option<String>,
+) -> Result<Option<String>> {
+    let head_sha = state
+        .github
+        .get_pull_request_head_sha(&repo.full_name, pr_number)
+        .await?;
+
+    match requested {
+        Some(requested) if !head_sha.starts_with(&requested) => {
+            info!(
+                "Refusing command for {} on {}#{}, the head is at {}",
+                requested, repo.full_name, pr_number, head_sha
+            );
+            state
+                .github
+                .comment_on_pr(
+                    &repo.full_name,
+                    pr_number,
+                    &format!(
+                        ":warning: {} is not the head of this PR, which is at {}. Review the new commits and run the command again.",
+                        requested, head_sha
+                    ),
+                )
+                .await?;
+            Ok(None)
+        }
+        _ => Ok(Some(head_sha)),
+    }
+}
+
+// Queues a try job for the workers, at most one per PR at a time
 async fn execute_try_merge(
     state: &AppState,
     repo: &Repository,
     pr_number: i32,
     branch_prefix: &str,
+    head_sha: &str,
+    requested_by: &str,
+    comment_id: i64,
 ) -> Result<()> {
     let job_key = format!("{}#{}", repo.full_name, pr_number);
 
-    // Check if job is already running
-    {
-        let active_jobs = state.active_jobs.read().await;
-        if active_jobs.contains_key(&job_key) {
-            info!("Job already running for {}", job_key);
-            return Ok(());
-        }
+    // Check if job is already queued or running
+    let active_jobs = state.db.get_active_jobs(repo.id).await?;
+    if active_jobs.iter().any(|job| job.pr_number == pr_number) {
+        info!("Job already queued or running for {}", job_key);
+        return Ok(());
     }
 
     // Create new job
@@ -200,45 +813,119 @@ async fn execute_try_merge(
         repository_id: repo.id,
         pr_number,
         branch_name: format!("{}/{}", branch_prefix, pr_number),
-        status: "running".to_string(),
+        status: JobStatus::Pending.to_string(),
         created_at: Utc::now(),
         updated_at: Utc::now(),
         error_message: None,
     };
 
-    // Store job in active jobs
-    {
-        let mut active_jobs = state.active_jobs.write().await;
-        active_jobs.insert(job_key.clone(), job.clone());
-    }
+    // Store job in database, where a worker picks it up
+    let check_run_id = create_check_run(state, repo, head_sha, TRY_CHECK_NAME).await;
+    state
+        .db
+        .create_try_merge_job(&job, head_sha, comment_id, check_run_id, requested_by)
+        .await?;
+    state.jobs.notify();
 
-    // Store job in database
-    state.db.create_try_merge_job(&job).await?;
+    info!("Queued try job {} for {}", job.id, job_key);
+    Ok(())
+}
+
+// Runs a try job leased by a worker to completion and reports the result
+pub async fn run_try_merge(state: &AppState, repo: &Repository, leased: &LeasedJob) -> Result<()> {
+    let job = &leased.job;
+    let job_key = format!("{}#{}", repo.full_name, job.pr_number);
+
+    // Replicas share the job queue, the lock makes sure only one of them pushes the try branch
+    let Some(lock) = RepoLock::try_acquire(state, repo, &job.branch_name).await? else {
+        anyhow::bail!("{} is locked by another job", job.branch_name);
+    };
+
+    if let Some(comment_id) = leased.comment_id {
+        react(state, repo, comment_id, "rocket").await;
+    }
 
     // Execute merge operation
-    let result = perform_try_merge(state, repo, pr_number, &job.branch_name).await;
+    let result = perform_try_merge(
+        state,
+        repo,
+        job.pr_number,
+        &job.branch_name,
+        leased.head_sha.as_deref(),
+        leased.check_run_id,
+        &lock,
+    )
+    .await;
 
     // Update job status
     let mut updated_job = job.clone();
-    match result {
-        Ok(_) => {
-            updated_job.status = "completed".to_string();
+    let (status, report) = match result {
+        Ok(report) if report.state == CiState::Success => {
             info!("Try merge completed successfully for {}", job_key);
+            (JobStatus::Completed, Some(report))
+        }
+        Ok(report) => {
+            updated_job.error_message = Some(format!("CI {}", report.summary()));
+            error!("Try merge failed for {}: CI {}", job_key, report.state);
+            (JobStatus::Failed, Some(report))
         }
         Err(e) => {
-            updated_job.status = "failed".to_string();
             updated_job.error_message = Some(e.to_string());
             error!("Try merge failed for {}: {}", job_key, e);
+            (JobStatus::Failed, None)
         }
-    }
+    };
 
+    updated_job.status = status.to_string();
     updated_job.updated_at = Utc::now();
-    state.db.update_try_merge_job(&updated_job).await?;
+    let updated = jobs::transition(
+        state,
+        job.id,
+        JobStatus::Running,
+        status,
+        Some(&leased.lease_owner),
+        updated_job.error_message.as_deref(),
+    )
+    .await;
+    lock.release().await?;
+    if !updated? {
+        info!(
+            "Try job for {} changed status meanwhile, not reporting",
+            job_key
+        );
+        return Ok(());
+    }
+
+    if let Some(comment_id) = leased.comment_id {
+        let reaction = if updated_job.error_message.is_some() {
+            "-1"
+        } else {
+            "+1"
+        };
+        react(state, repo, comment_id, reaction).await;
+    }
 
-    // Remove from active jobs
+    let summary = try_result_comment(&updated_job, report.as_ref());
+    let (conclusion, title) = match updated_job.error_message {
+        None => ("success", "Try build successful"),
+        Some(_) => ("failure", "Try build failed"),
+    };
+    update_check_run(
+        state,
+        repo,
+        leased.check_run_id,
+        "completed",
+        Some(conclusion),
+        Some(&check_run_output(title, &summary, report.as_ref())),
+    )
+    .await;
+
+    if let Err(e) = state
+        .github
+        .comment_on_pr(&repo.full_name, job.pr_number, &summary)
+        .await
     {
-        let mut active_jobs = state.active_jobs.write().await;
-        active_jobs.remove(&job_key);
+        error!("Failed to report try result for {}: {}", job_key, e);
     }
 
     Ok(())
@@ -249,40 +936,98 @@ async fn perform_try_merge(
     repo: &Repository,
     pr_number: i32,
     branch_name: &str,
-) -> Result<()> {
-    // Get PR details
-    let pr = state
+    head_sha: Option<&str>,
+    check_run_id: Option<i64>,
+    lock: &RepoLock,
+) -> Result<CiReport> {
+    // Only the commit the try was requested for is built
+    let current_sha = state
         .github
-        .get_pull_request(&repo.full_name, pr_number)
+        .get_pull_request_head_sha(&repo.full_name, pr_number)
         .await?;
+    let head_sha = match head_sha {
+        Some(requested) if requested != current_sha => anyhow::bail!(
+            "The PR head moved from {} to {} since the try was requested",
+            requested,
+            current_sha
+        ),
+        Some(requested) => requested.to_string(),
+        None => current_sha,
+    };
 
     // Create or update the try branch
+    lock.ensure_held().await?;
     state
         .github
         .create_try_branch(
             &repo.full_name,
-            &pr.head_branch,
+            &head_sha,
             &repo.default_branch,
             branch_name,
         )
         .await?;
-
-    // Wait for CI to complete (simplified)
-    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+    update_check_run(state, repo, check_run_id, "in_progress", None, None).await;
 
     // Check if merge is successful
-    let status = state
-        .github
-        .get_branch_status(&repo.full_name, branch_name)
-        .await?;
+    let report = wait_for_ci(state, repo, branch_name).await?;
+    info!(
+        "Try merge for {}/{} finished with CI {}",
+        repo.full_name, pr_number, report.state
+    );
+
+    Ok(report)
+}
 
-    if status == "success" {
-        info!("Try merge successful for {}/{}", repo.full_name, pr_number);
+fn try_result_comment(job: &TryMergeJob, report: Option<&CiReport>) -> String {
+    let mut lines = vec![match &job.error_message {
+        None => ":sunny: Try build successful".to_string(),
+        Some(error_message) => format!(":broken_heart: Try build failed: {}", error_message),
+    }];
+    lines.push(String::new());
+
+    if let Some(report) = report {
+        lines.push(format!("- Merge commit: {}", report.sha));
+    }
+    lines.push(format!("- Try branch: `{}`", job.branch_name));
+    lines.push(format!(
+        "- Duration: {}",
+        format_duration(job.updated_at - job.created_at)
+    ));
+
+    if let Some(report) = report.filter(|report| !report.checks.is_empty()) {
+        lines.push(String::new());
+        lines.push(report.checks_table());
+    }
+
+    lines.join("\n")
+}
+
+// e.g. "1h 4m 12s"
+fn format_duration(duration: chrono::Duration) -> String {
+    let seconds = duration.num_seconds().max(0);
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
     } else {
-        anyhow::bail!("Try merge failed with status: {}", status);
+        format!("{}s", seconds)
     }
+}
 
-    Ok(())
+// Reactions are best-effort feedback, failing to add one never fails the command
+pub async fn react(state: &AppState, repo: &Repository, comment_id: i64, content: &str) {
+    if let Err(e) = state
+        .github
+        .add_reaction(&repo.full_name, comment_id, content)
+        .await
+    {
+        warn!(
+            "Failed to add {} reaction to comment {}: {}",
+            content, comment_id, e
+        );
+    }
 }
 
 async fn health_check() -> Json<serde_json::Value> {
@@ -0,0 +1,219 @@
+// migrations.rs
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+// Up-migrations in the order they are applied. Applied migrations must never be edited;
+// schema changes go into a new file with the next version. They are written for both
+// PostgreSQL and CockroachDB, and the early ones use IF NOT EXISTS so that databases
+// created before migrations were versioned adopt them cleanly.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "merge_queue_batches",
+        sql: include_str!("../migrations/0002_merge_queue_batches.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "delegations",
+        sql: include_str!("../migrations/0003_delegations.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "try_job_leases",
+        sql: include_str!("../migrations/0004_try_job_leases.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "repository_locks",
+        sql: include_str!("../migrations/0005_repository_locks.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "pinned_head_sha",
+        sql: include_str!("../migrations/0006_pinned_head_sha.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "repository_installations",
+        sql: include_str!("../migrations/0007_repository_installations.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "try_job_status_check",
+        sql: include_str!("../migrations/0008_try_job_status_check.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "audit_events",
+        sql: include_str!("../migrations/0009_audit_events.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "audit_events_subject",
+        sql: include_str!("../migrations/0010_audit_events_subject.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "merge_queue_active_entry",
+        sql: include_str!("../migrations/0011_merge_queue_active_entry.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "merge_queue_active_entry_index",
+        sql: include_str!("../migrations/0012_merge_queue_active_entry_index.sql"),
+    },
+];
+
+impl Migration {
+    fn checksum(&self) -> String {
+        hex::encode(Sha256::digest(self.sql.as_bytes()))
+    }
+}
+
+// Brings the schema up to the latest migration. Refuses to start against a schema
+// migrated by a newer build, or one whose applied migrations were edited since.
+pub async fn run(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version BIGINT PRIMARY KEY,
+            name TEXT NOT NULL,
+            checksum TEXT NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    let mut applied = applied_migrations(pool).await?;
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+
+    if let Some(newest) = applied.keys().max().filter(|newest| **newest > latest) {
+        anyhow::bail!(
+            "The database schema is at version {} but this build only knows versions up to {}; refusing to run against a newer schema",
+            newest,
+            latest
+        );
+    }
+
+    for migration in MIGRATIONS {
+        if !applied.contains_key(&migration.version) {
+            if let Err(e) = apply(pool, migration).await {
+                // Another replica starting at the same time may have applied it first
+                applied = applied_migrations(pool).await?;
+                if !applied.contains_key(&migration.version) {
+                    return Err(e);
+                }
+                warn!(
+                    "Migration {} was applied concurrently by another instance",
+                    migration.version
+                );
+            }
+        }
+
+        if applied
+            .get(&migration.version)
+            .is_some_and(|checksum| *checksum != migration.checksum())
+        {
+            anyhow::bail!(
+                "Migration {} ({}) was changed after it was applied",
+                migration.version,
+                migration.name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn applied_migrations(pool: &PgPool) -> Result<HashMap<i64, String>> {
+    let rows = sqlx::query("SELECT version, checksum FROM schema_migrations")
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get("version"), row.get("checksum")))
+        .collect())
+}
+
+// The migration and its record commit together. CockroachDB allows writes after schema
+// changes in a transaction but not before, so the record is inserted last.
+async fn apply(pool: &PgPool, migration: &Migration) -> Result<()> {
+    info!(
+        "Applying migration {} ({})",
+        migration.version, migration.name
+    );
+
+    let mut tx = pool.begin().await?;
+    sqlx::raw_sql(migration.sql)
+        .execute(&mut *tx)
+        .await
+        .with_context(|| {
+            format!(
+                "Migration {} ({}) failed",
+                migration.version, migration.name
+            )
+        })?;
+    sqlx::query("INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)")
+        .bind(migration.version)
+        .bind(migration.name)
+        .bind(migration.checksum())
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_unique_and_increasing() {
+        let versions: Vec<i64> = MIGRATIONS
+            .iter()
+            .map(|migration| migration.version)
+            .collect();
+        assert_eq!(versions.first(), Some(&1));
+        for pair in versions.windows(2) {
+            assert!(
+                pair[0] < pair[1],
+                "{} is listed before {}",
+                pair[0],
+                pair[1]
+            );
+        }
+    }
+
+    #[test]
+    fn names_and_contents_are_unique() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert!(
+                !migration.sql.trim().is_empty(),
+                "{} is empty",
+                migration.name
+            );
+            for other in &MIGRATIONS[i + 1..] {
+                assert_ne!(migration.name, other.name);
+                assert_ne!(migration.checksum(), other.checksum());
+            }
+        }
+    }
+}
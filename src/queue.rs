@@ -0,0 +1,1064 @@
+// queue.rs
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use github_merge_bot::Repository;
+use std::{collections::VecDeque, fmt, sync::Arc};
+use tokio::time::{sleep, Duration};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    ci::{
+        check_run_output, create_check_run, update_check_run, wait_for_ci, CiState,
+        MERGE_CHECK_NAME,
+    },
+    commands::RollupMode,
+    locks::{RepoLock, MERGE_QUEUE_LOCK},
+    react,
+    repo_config::{MergeMethod, RepoConfig},
+    AppState,
+};
+
+#[derive(Debug, Clone)]
+pub struct QueueEntry {
+    pub id: Uuid,
+    pub repository_id: i64,
+    pub pr_number: i32,
+    pub approved_by: String,
+    pub priority: i32,
+    pub status: QueueStatus,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub error_message: Option<String>,
+    pub batch_id: Option<Uuid>,
+    pub rollup: RollupMode,
+    // Comment holding the approval, reacted to as the entry moves through the queue
+    pub comment_id: Option<i64>,
+    // The bot's merge check run on the PR head commit
+    pub check_run_id: Option<i64>,
+    // The PR head commit that was approved, and the only one that may land.
+    // None for entries approved before approvals were pinned.
+    pub head_sha: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueStatus {
+    Queued,
+    Testing,
+    Merged,
+    Failed,
+    Cancelled,
+}
+
+impl QueueStatus {
+    pub const ALL: &'static [QueueStatus] = &[
+        QueueStatus::Queued,
+        QueueStatus::Testing,
+        QueueStatus::Merged,
+        QueueStatus::Failed,
+        QueueStatus::Cancelled,
+    ];
+
+    // Entries of PRs still waiting to land; a PR has at most one
+    pub const ACTIVE: &'static [QueueStatus] = &[QueueStatus::Queued, QueueStatus::Testing];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueStatus::Queued => "queued",
+            QueueStatus::Testing => "testing",
+            QueueStatus::Merged => "merged",
+            QueueStatus::Failed => "failed",
+            QueueStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "queued" => Some(QueueStatus::Queued),
+            "testing" => Some(QueueStatus::Testing),
+            "merged" => Some(QueueStatus::Merged),
+            "failed" => Some(QueueStatus::Failed),
+            "cancelled" => Some(QueueStatus::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for QueueStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// An `r+` or `r=<user>` to record on a PR
+#[derive(Debug, Clone)]
+pub struct Approval<'a> {
+    pub approved_by: &'a str,
+    // The PR head commit the approval is pinned to
+    pub head_sha: &'a str,
+    pub priority: Option<i32>,
+    pub rollup: Option<RollupMode>,
+    // Comment holding the command
+    pub comment_id: i64,
+}
+
+pub async fn approve(
+    state: &AppState,
+    repo: &Repository,
+    pr_number: i32,
+    approval: Approval<'_>,
+) -> Result<()> {
+    let Approval {
+        approved_by,
+        head_sha,
+        priority,
+        rollup,
+        comment_id,
+    } = approval;
+
+    if let Some(mut entry) = state.db.get_queue_entry(repo.id, pr_number).await? {
+        info!(
+            "PR {}#{} is already in the merge queue ({})",
+            repo.full_name, pr_number, entry.status
+        );
+
+        // Re-approving a queued PR with arguments changes its priority or rollup mode,
+        // and pins it to the head it was re-approved at
+        let moved = entry.head_sha.as_deref() != Some(head_sha);
+        let message = if entry.status == QueueStatus::Queued
+            && (priority.is_some() || rollup.is_some() || moved)
+        {
+            entry.priority = priority.unwrap_or(entry.priority);
+            entry.rollup = rollup.unwrap_or(entry.rollup);
+            entry.head_sha = Some(head_sha.to_string());
+            entry.updated_at = Utc::now();
+            state.db.update_queue_entry(&entry).await?;
+            react(state, repo, comment_id, "+1").await;
+            format!(
+                ":pushpin: Updated queue entry: approved at {}, priority {}, rollup {}.",
+                head_sha,
+                entry.priority,
+                entry.rollup.as_str()
+            )
+        } else {
+            format!(
+                ":information_source: Already approved by @{} and {}.",
+                entry.approved_by,
+                if entry.status == QueueStatus::Testing {
+                    "currently testing"
+                } else {
+                    "waiting in the merge queue"
+                }
+            )
+        };
+
+        state
+            .github
+            .comment_on_pr(&repo.full_name, pr_number, &message)
+            .await?;
+        return Ok(());
+    }
+
+    let mut entry = QueueEntry {
+        id: Uuid::new_v4(),
+        repository_id: repo.id,
+        pr_number,
+        approved_by: approved_by.to_string(),
+        priority: priority.unwrap_or(0),
+        status: QueueStatus::Queued,
+        created_at: Utc::now(),
+        updated_at: Utc::now(),
+        error_message: None,
+        batch_id: None,
+        rollup: rollup.unwrap_or(RollupMode::Maybe),
+        comment_id: Some(comment_id),
+        check_run_id: None,
+        head_sha: Some(head_sha.to_string()),
+    };
+
+    // A concurrent approval of the same PR got in first. The check run is only
+    // created once the entry exists, so the other entry's check run stays current.
+    if !state.db.enqueue_pull_request(&entry).await? {
+        info!(
+            "PR {}#{} was queued concurrently, ignoring the approval by {}",
+            repo.full_name, pr_number, approved_by
+        );
+        state
+            .github
+            .comment_on_pr(
+                &repo.full_name,
+                pr_number,
+                ":information_source: Already approved and waiting in the merge queue.",
+            )
+            .await?;
+        return Ok(());
+    }
+
+    entry.check_run_id = create_check_run(state, repo, head_sha, MERGE_CHECK_NAME).await;
+    state
+        .db
+        .set_queue_check_run(entry.id, entry.check_run_id)
+        .await?;
+
+    let queue = state.db.get_merge_queue(repo.id).await?;
+    let position = queue
+        .iter()
+        .position(|queued| queued.id == entry.id)
+        .map(|index| index + 1)
+        .unwrap_or(queue.len());
+
+    info!(
+        "Queued {}#{} at position {} (approved by {})",
+        repo.full_name, pr_number, position, approved_by
+    );
+    state
+        .github
+        .comment_on_pr(
+            &repo.full_name,
+            pr_number,
+            &format!(
+                ":pushpin: Approved by @{} at {}, queued at position {}.",
+                approved_by, head_sha, position
+            ),
+        )
+        .await?;
+
+    Ok(())
+}
+
+// Removes the PR from the merge queue, recording the reason on its entry. A PR
+// cancelled while its batch is testing is dropped from the batch before it lands.
+// Returns the status the entry had, and how many workflow runs were cancelled.
+pub async fn cancel(
+    state: &AppState,
+    repo: &Repository,
+    pr_number: i32,
+    reason: &str,
+) -> Result<Option<(QueueStatus, usize)>> {
+    let Some(mut entry) = state.db.get_queue_entry(repo.id, pr_number).await? else {
+        return Ok(None);
+    };
+
+    let previous = entry.status;
+    entry.batch_id = None;
+    set_status(
+        state,
+        repo,
+        &mut entry,
+        QueueStatus::Cancelled,
+        Some(reason.to_string()),
+    )
+    .await?;
+    info!(
+        "Removed {}#{} from the merge queue ({}): {}",
+        repo.full_name, pr_number, previous, reason
+    );
+
+    let runs = if previous == QueueStatus::Testing {
+        stop_batch(state, repo).await
+    } else {
+        0
+    };
+
+    Ok(Some((previous, runs)))
+}
+
+// Keeps the PR's approval across new commits: the entry is pinned to the new head commit
+// and waits in the queue again. A batch built from the previous commits is retested.
+pub async fn requeue(
+    state: &AppState,
+    repo: &Repository,
+    pr_number: i32,
+    head_sha: &str,
+) -> Result<()> {
+    let Some(mut entry) = state.db.get_queue_entry(repo.id, pr_number).await? else {
+        return Ok(());
+    };
+
+    let previous = entry.status;
+    update_check_run(
+        state,
+        repo,
+        entry.check_run_id,
+        "completed",
+        Some("cancelled"),
+        Some(&check_run_output(
+            "Superseded",
+            "New commits were pushed to the PR.",
+            None,
+        )),
+    )
+    .await;
+    entry.check_run_id = create_check_run(state, repo, head_sha, MERGE_CHECK_NAME).await;
+    entry.head_sha = Some(head_sha.to_string());
+    entry.batch_id = None;
+    set_status(state, repo, &mut entry, QueueStatus::Queued, None).await?;
+    info!(
+        "Requeued {}#{} after new commits ({})",
+        repo.full_name, pr_number, previous
+    );
+
+    if previous == QueueStatus::Testing {
+        stop_batch(state, repo).await;
+    }
+
+    Ok(())
+}
+
+// Cancels the workflow runs of the batch being tested so it is rebuilt without waiting for
+// CI, returning how many were cancelled
+async fn stop_batch(state: &AppState, repo: &Repository) -> usize {
+    let merge_branch = match state.repo_configs.get(&state.github, repo).await {
+        Ok(repo_config) => repo_config.merge_branch,
+        Err(e) => {
+            error!("Could not load configuration of {}: {}", repo.full_name, e);
+            return 0;
+        }
+    };
+
+    match state
+        .github
+        .cancel_workflow_runs(&repo.full_name, &merge_branch)
+        .await
+    {
+        Ok(cancelled) => cancelled,
+        Err(e) => {
+            error!("Could not cancel workflow runs of {}: {}", merge_branch, e);
+            0
+        }
+    }
+}
+
+// Processes the repository's merge queue in the background. Landing takes as long as
+// CI, so it never runs on the repository's command worker. After an error, e.g. GitHub
+// being unreachable, processing is retried until the queue is empty.
+pub fn spawn_processing(state: Arc<AppState>, repo: Repository) {
+    tokio::spawn(async move {
+        let retry = Duration::from_secs(state.config.ci_poll_interval_secs.max(1));
+        while let Err(e) = process_queue(&state, &repo).await {
+            error!("Error processing merge queue of {}: {}", repo.full_name, e);
+            sleep(retry).await;
+        }
+    });
+}
+
+// Lands queued PRs until the queue for the repository is empty. Only the holder of
+// the repository's merge queue lock processes it; when another task or replica holds
+// the lock, it picks up the new entries itself.
+pub async fn process_queue(state: &AppState, repo: &Repository) -> Result<()> {
+    loop {
+        let Some(lock) = RepoLock::try_acquire(state, repo, MERGE_QUEUE_LOCK).await? else {
+            info!(
+                "Merge queue of {} is being processed elsewhere",
+                repo.full_name
+            );
+            return Ok(());
+        };
+        info!(
+            "Processing merge queue of {} (fencing token {})",
+            repo.full_name,
+            lock.fencing_token()
+        );
+
+        let result = match requeue_stale(state, repo).await {
+            Ok(()) => drain_queue(state, repo, &lock).await,
+            Err(e) => Err(e),
+        };
+        lock.release().await?;
+        result?;
+
+        // Entries approved while the lock was held found it taken and left them to us
+        if state.db.get_merge_queue(repo.id).await?.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+// Only the lock holder tests batches, so entries still testing when the lock is taken
+// were left behind by a holder that stopped, e.g. on a restart. They are tested again.
+async fn requeue_stale(state: &AppState, repo: &Repository) -> Result<()> {
+    let stale = state
+        .db
+        .list_queue_entries(repo.id, &[QueueStatus::Testing], None, i64::MAX, 0)
+        .await?;
+
+    for mut entry in stale {
+        info!(
+            "Requeueing {}#{}, left testing by a previous run",
+            repo.full_name, entry.pr_number
+        );
+        entry.batch_id = None;
+        set_status(state, repo, &mut entry, QueueStatus::Queued, None).await?;
+    }
+
+    Ok(())
+}
+
+async fn drain_queue(state: &AppState, repo: &Repository, lock: &RepoLock) -> Result<()> {
+    loop {
+        let repo_config = state.repo_configs.get(&state.github, repo).await?;
+        let batch = next_batch(state, repo).await?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        // Errors such as GitHub being unreachable say nothing about the PRs, so the
+        // entries of the batch go back to the queue rather than staying stuck testing
+        let batch_id = Uuid::new_v4();
+        let result = test_batch(state, repo, &repo_config, lock, batch, batch_id).await;
+        if let Err(e) = requeue_batch(state, repo, batch_id).await {
+            error!(
+                "Could not requeue the rest of batch {} in {}: {}",
+                batch_id, repo.full_name, e
+            );
+        }
+        result?;
+    }
+}
+
+// Puts the entries of the batch that are still testing back in the queue. Entries the
+// queue moved on with, e.g. a replica that took the lock over, are left alone.
+async fn requeue_batch(state: &AppState, repo: &Repository, batch_id: Uuid) -> Result<()> {
+    let testing = state
+        .db
+        .list_queue_entries(repo.id, &[QueueStatus::Testing], None, i64::MAX, 0)
+        .await?;
+
+    for mut entry in testing {
+        if entry.batch_id != Some(batch_id) {
+            continue;
+        }
+        info!(
+            "Requeueing {}#{}, its batch stopped before a verdict",
+            repo.full_name, entry.pr_number
+        );
+        entry.batch_id = None;
+        set_status(state, repo, &mut entry, QueueStatus::Queued, None).await?;
+    }
+
+    Ok(())
+}
+
+async fn test_batch(
+    state: &AppState,
+    repo: &Repository,
+    repo_config: &RepoConfig,
+    lock: &RepoLock,
+    mut batch: Vec<Candidate>,
+    batch_id: Uuid,
+) -> Result<()> {
+    let numbers: Vec<i32> = batch.iter().map(|c| c.entry.pr_number).collect();
+    for candidate in batch.iter_mut() {
+        candidate.entry.batch_id = Some(batch_id);
+        set_status(
+            state,
+            repo,
+            &mut candidate.entry,
+            QueueStatus::Testing,
+            None,
+        )
+        .await?;
+
+        let message = if numbers.len() == 1 {
+            format!(
+                ":hourglass: Testing merge of #{} on `{}`.",
+                candidate.entry.pr_number, repo_config.merge_branch
+            )
+        } else {
+            format!(
+                ":hourglass: Testing #{} in a batch of {} on `{}`.",
+                candidate.entry.pr_number,
+                format_prs(&numbers),
+                repo_config.merge_branch
+            )
+        };
+        comment(state, repo, candidate.entry.pr_number, &message).await;
+    }
+
+    // A failing batch is split in halves that are tested in order, so the
+    // green half lands and the culprit ends up alone in a batch of one.
+    let bisecting = batch.len() > 1;
+    let mut pending = VecDeque::from([batch]);
+    while let Some(batch) = pending.pop_front() {
+        let (batch, outcome) = build_and_test(state, repo, repo_config, lock, batch).await?;
+        if batch.is_empty() {
+            continue;
+        }
+
+        match outcome {
+            Ok((base_branch, sha)) => {
+                info!(
+                    "Landed {} in {} on {} at {}",
+                    format_prs(&pr_numbers(&batch)),
+                    repo.full_name,
+                    base_branch,
+                    sha
+                );
+                let message = if batch.len() == 1 {
+                    format!(
+                        ":sunny: Tests passed, `{}` fast-forwarded to {}.",
+                        base_branch, sha
+                    )
+                } else {
+                    format!(
+                        ":sunny: Tests passed for {}, `{}` fast-forwarded to {}.",
+                        format_prs(&pr_numbers(&batch)),
+                        base_branch,
+                        sha
+                    )
+                };
+                for mut candidate in batch {
+                    set_status(state, repo, &mut candidate.entry, QueueStatus::Merged, None)
+                        .await?;
+                    comment(state, repo, candidate.entry.pr_number, &message).await;
+                }
+            }
+            Err(e) if batch.len() > 1 => {
+                info!(
+                    "Batch {} failed in {}, bisecting: {}",
+                    format_prs(&pr_numbers(&batch)),
+                    repo.full_name,
+                    e
+                );
+                let message = format!(
+                    ":mag: Batch of {} failed ({}), bisecting to find the culprit.",
+                    format_prs(&pr_numbers(&batch)),
+                    e
+                );
+                for candidate in &batch {
+                    comment(state, repo, candidate.entry.pr_number, &message).await;
+                }
+
+                let (left, right) = bisect(batch);
+                pending.push_front(right);
+                pending.push_front(left);
+            }
+            Err(e) => {
+                let mut candidate = batch.into_iter().next().expect("batch is not empty");
+                error!(
+                    "Merge failed for {}#{}: {}",
+                    repo.full_name, candidate.entry.pr_number, e
+                );
+                set_status(
+                    state,
+                    repo,
+                    &mut candidate.entry,
+                    QueueStatus::Failed,
+                    Some(e.to_string()),
+                )
+                .await?;
+
+                let message = if bisecting {
+                    format!(
+                        ":broken_heart: #{} broke the batch: {}",
+                        candidate.entry.pr_number, e
+                    )
+                } else {
+                    format!(":broken_heart: Merge failed: {}", e)
+                };
+                comment(state, repo, candidate.entry.pr_number, &message).await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Splits a failed batch in halves, the first one holding the PRs ahead in the queue
+fn bisect<T>(mut batch: Vec<T>) -> (Vec<T>, Vec<T>) {
+    let right = batch.split_off(batch.len() / 2);
+    (batch, right)
+}
+
+struct Candidate {
+    entry: QueueEntry,
+    base_branch: String,
+    title: String,
+}
+
+// Takes queued PRs, up to the batch size, that share the first PR's base branch. PRs
+// approved with `rollup=never` are only ever tested on their own.
+async fn next_batch(state: &AppState, repo: &Repository) -> Result<Vec<Candidate>> {
+    let mut batch: Vec<Candidate> = Vec::new();
+
+    for mut entry in batch_order(state.db.get_merge_queue(repo.id).await?) {
+        if batch.len() >= state.config.max_batch_size.max(1) {
+            break;
+        }
+
+        let pr = state
+            .github
+            .get_pull_request(&repo.full_name, entry.pr_number)
+            .await?;
+
+        if pr.state != "open" {
+            info!(
+                "Dropping {}#{} from the merge queue: PR is {}",
+                repo.full_name, entry.pr_number, pr.state
+            );
+            set_status(
+                state,
+                repo,
+                &mut entry,
+                QueueStatus::Failed,
+                Some(format!("PR is {}", pr.state)),
+            )
+            .await?;
+            continue;
+        }
+
+        if let Some(first) = batch.first() {
+            match joins(first, entry.rollup, &pr.base_branch) {
+                None => break,
+                Some(false) => continue,
+                Some(true) => {}
+            }
+        }
+
+        batch.push(Candidate {
+            entry,
+            base_branch: pr.base_branch,
+            title: pr.title,
+        });
+    }
+
+    Ok(batch)
+}
+
+// Whether a PR may join the batch started by `first`, or None once the batch is closed
+// to every other PR
+fn joins(first: &Candidate, rollup: RollupMode, base_branch: &str) -> Option<bool> {
+    if first.entry.rollup == RollupMode::Never {
+        return None;
+    }
+    Some(first.base_branch == base_branch && rollup != RollupMode::Never)
+}
+
+// The order queued entries are considered in for the next batch. The head of the queue
+// always goes first; the rest of the batch is filled with `rollup=always` PRs before
+// `rollup=maybe` ones, each in landing order.
+fn batch_order(mut queue: Vec<QueueEntry>) -> Vec<QueueEntry> {
+    if let Some(rest) = queue.get_mut(1..) {
+        rest.sort_by_key(|entry| entry.rollup != RollupMode::Always);
+    }
+    queue
+}
+
+async fn set_status(
+    state: &AppState,
+    repo: &Repository,
+    entry: &mut QueueEntry,
+    status: QueueStatus,
+    error_message: Option<String>,
+) -> Result<()> {
+    entry.status = status;
+    entry.error_message = error_message;
+    entry.updated_at = Utc::now();
+    state.db.update_queue_entry(entry).await?;
+
+    if let Some(comment_id) = entry.comment_id {
+        match status {
+            QueueStatus::Testing => react(state, repo, comment_id, "rocket").await,
+            QueueStatus::Merged => react(state, repo, comment_id, "+1").await,
+            QueueStatus::Failed => react(state, repo, comment_id, "-1").await,
+            QueueStatus::Queued | QueueStatus::Cancelled => {}
+        }
+    }
+
+    let output = |title: &str| {
+        let summary = entry
+            .error_message
+            .clone()
+            .unwrap_or_else(|| format!("Approved by @{}.", entry.approved_by));
+        check_run_output(title, &summary, None)
+    };
+    match status {
+        QueueStatus::Queued => {
+            update_check_run(state, repo, entry.check_run_id, "queued", None, None).await
+        }
+        QueueStatus::Testing => {
+            update_check_run(state, repo, entry.check_run_id, "in_progress", None, None).await
+        }
+        QueueStatus::Merged => {
+            update_check_run(
+                state,
+                repo,
+                entry.check_run_id,
+                "completed",
+                Some("success"),
+                Some(&output("Merged")),
+            )
+            .await
+        }
+        QueueStatus::Failed => {
+            update_check_run(
+                state,
+                repo,
+                entry.check_run_id,
+                "completed",
+                Some("failure"),
+                Some(&output("Merge failed")),
+            )
+            .await
+        }
+        QueueStatus::Cancelled => {
+            update_check_run(
+                state,
+                repo,
+                entry.check_run_id,
+                "completed",
+                Some("cancelled"),
+                Some(&output("Merge cancelled")),
+            )
+            .await
+        }
+    }
+
+    Ok(())
+}
+
+// Merges every PR of the batch onto the staging branch, waits for CI and lands the
+// batch with the repository's merge method when it passes. PRs that conflict are
+// failed and dropped from the returned batch; the outcome holds the base branch and
+// the SHA it now points at. The lock is checked before every push.
+async fn build_and_test(
+    state: &AppState,
+    repo: &Repository,
+    repo_config: &RepoConfig,
+    lock: &RepoLock,
+    batch: Vec<Candidate>,
+) -> Result<(Vec<Candidate>, Result<(String, String)>)> {
+    let (batch, _) = drop_cancelled(state, repo, batch).await?;
+    let Some(base_branch) = batch.first().map(|c| c.base_branch.clone()) else {
+        return Ok((batch, Err(anyhow::anyhow!("Empty batch"))));
+    };
+
+    let base_sha = state
+        .github
+        .get_branch_sha(&repo.full_name, &base_branch)
+        .await?;
+    lock.ensure_held().await?;
+    state
+        .github
+        .reset_branch(&repo.full_name, &repo_config.merge_branch, &base_sha)
+        .await?;
+
+    let mut merged = Vec::new();
+    for mut candidate in batch {
+        lock.ensure_held().await?;
+        // Only the approved commit lands, a PR whose head moved since is refused
+        let result = match state
+            .github
+            .get_pull_request_head_sha(&repo.full_name, candidate.entry.pr_number)
+            .await
+        {
+            Ok(head_sha) => match &candidate.entry.head_sha {
+                Some(approved) if *approved != head_sha => Err(anyhow::anyhow!(
+                    "the PR head moved from {} to {} since it was approved",
+                    approved,
+                    head_sha
+                )),
+                // With the merge method the base branch is fast-forwarded onto this
+                // commit, so its message ends up in the base branch history
+                _ => {
+                    let message = format!(
+                        "Merge #{}: {} (r={})",
+                        candidate.entry.pr_number, candidate.title, candidate.entry.approved_by
+                    );
+                    state
+                        .github
+                        .merge_branch(
+                            &repo.full_name,
+                            &repo_config.merge_branch,
+                            &head_sha,
+                            &message,
+                        )
+                        .await
+                }
+            },
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => merged.push(candidate),
+            Err(e) => {
+                error!(
+                    "Could not merge {}#{} onto {}: {}",
+                    repo.full_name, candidate.entry.pr_number, repo_config.merge_branch, e
+                );
+                set_status(
+                    state,
+                    repo,
+                    &mut candidate.entry,
+                    QueueStatus::Failed,
+                    Some(e.to_string()),
+                )
+                .await?;
+                comment(
+                    state,
+                    repo,
+                    candidate.entry.pr_number,
+                    &format!(":lock: Could not be merged onto `{}`: {}", base_branch, e),
+                )
+                .await;
+            }
+        }
+    }
+
+    if merged.is_empty() {
+        return Ok((merged, Err(anyhow::anyhow!("Nothing to test"))));
+    }
+
+    let ci = wait_for_ci(state, repo, &repo_config.merge_branch).await;
+
+    // The tested commit contains PRs cancelled or pushed to during CI, so it must not
+    // land. The rest of the batch goes back to the queue to be rebuilt.
+    let (merged, cancelled) = drop_cancelled(state, repo, merged).await?;
+    if cancelled {
+        for mut candidate in merged {
+            candidate.entry.batch_id = None;
+            set_status(state, repo, &mut candidate.entry, QueueStatus::Queued, None).await?;
+        }
+        return Ok((Vec::new(), Err(anyhow::anyhow!("Batch cancelled"))));
+    }
+
+    // A timeout is the batch's verdict just like a red build
+    let report = match ci {
+        Ok(report) => report,
+        Err(e) => return Ok((merged, Err(e))),
+    };
+
+    if report.state != CiState::Success {
+        return Ok((
+            merged,
+            Err(anyhow::anyhow!(
+                "CI finished with status: {}",
+                report.summary()
+            )),
+        ));
+    }
+
+    lock.ensure_held().await?;
+    if repo_config.merge_method == MergeMethod::Merge {
+        let sha = report.sha;
+        if let Err(e) = state
+            .github
+            .fast_forward_branch(&repo.full_name, &base_branch, &sha)
+            .await
+        {
+            // Someone pushed to the base branch during CI. Nothing is wrong with the
+            // PRs, so they go back to the queue to be rebuilt on top of the new base.
+            // Any other refusal, e.g. by branch protection, would be the same next
+            // time, so it fails the batch.
+            let current_sha = state
+                .github
+                .get_branch_sha(&repo.full_name, &base_branch)
+                .await?;
+            if current_sha == base_sha {
+                return Ok((merged, Err(e)));
+            }
+
+            info!(
+                "{} moved while testing {}, requeueing the batch",
+                base_branch,
+                format_prs(&pr_numbers(&merged))
+            );
+            for mut candidate in merged {
+                candidate.entry.batch_id = None;
+                set_status(state, repo, &mut candidate.entry, QueueStatus::Queued, None).await?;
+            }
+            return Ok((Vec::new(), Err(anyhow::anyhow!("Base branch moved"))));
+        }
+
+        return Ok((merged, Ok((base_branch, sha))));
+    }
+
+    // Squash and rebase land each PR through the merge API in queue order. If one
+    // of them is rejected the PRs behind it go back to the queue to be rebuilt on
+    // top of what already landed.
+    let mut landed = Vec::new();
+    let mut sha = report.sha;
+    let mut remaining = merged.into_iter();
+    while let Some(mut candidate) = remaining.next() {
+        // The PRs that already landed are reported; the rest is requeued with the batch
+        if let Err(e) = lock.ensure_held().await {
+            error!("Stopped landing in {}: {}", repo.full_name, e);
+            break;
+        }
+        match state
+            .github
+            .merge_pull_request(
+                &repo.full_name,
+                candidate.entry.pr_number,
+                repo_config.merge_method.as_str(),
+                candidate.entry.head_sha.as_deref(),
+            )
+            .await
+        {
+            Ok(merge_sha) => {
+                sha = merge_sha;
+                landed.push(candidate);
+            }
+            Err(e) => {
+                error!(
+                    "Could not land {}#{}: {}",
+                    repo.full_name, candidate.entry.pr_number, e
+                );
+                set_status(
+                    state,
+                    repo,
+                    &mut candidate.entry,
+                    QueueStatus::Failed,
+                    Some(e.to_string()),
+                )
+                .await?;
+                comment(
+                    state,
+                    repo,
+                    candidate.entry.pr_number,
+                    &format!(":broken_heart: Tests passed but merging failed: {}", e),
+                )
+                .await;
+
+                for mut candidate in remaining.by_ref() {
+                    candidate.entry.batch_id = None;
+                    set_status(state, repo, &mut candidate.entry, QueueStatus::Queued, None)
+                        .await?;
+                }
+            }
+        }
+    }
+
+    Ok((landed, Ok((base_branch, sha))))
+}
+
+// Drops the candidates that are no longer testing, e.g. after being cancelled, returning
+// whether there were any
+async fn drop_cancelled(
+    state: &AppState,
+    repo: &Repository,
+    batch: Vec<Candidate>,
+) -> Result<(Vec<Candidate>, bool)> {
+    let count = batch.len();
+    let mut testing = Vec::new();
+
+    for candidate in batch {
+        let entry = state
+            .db
+            .get_queue_entry(repo.id, candidate.entry.pr_number)
+            .await?;
+        if entry.is_some_and(|entry| {
+            entry.id == candidate.entry.id && entry.status == QueueStatus::Testing
+        }) {
+            testing.push(candidate);
+        } else {
+            info!(
+                "Dropping {}#{} from its batch, it is no longer testing",
+                repo.full_name, candidate.entry.pr_number
+            );
+        }
+    }
+
+    let cancelled = testing.len() < count;
+    Ok((testing, cancelled))
+}
+
+// Comments on PRs in the queue are best-effort, failing to post one never stops the
+// batch and leaves its entries testing
+async fn comment(state: &AppState, repo: &Repository, pr_number: i32, message: &str) {
+    if let Err(e) = state
+        .github
+        .comment_on_pr(&repo.full_name, pr_number, message)
+        .await
+    {
+        warn!(
+            "Failed to comment on {}#{}: {}",
+            repo.full_name, pr_number, e
+        );
+    }
+}
+
+fn pr_numbers(batch: &[Candidate]) -> Vec<i32> {
+    batch.iter().map(|c| c.entry.pr_number).collect()
+}
+
+fn format_prs(numbers: &[i32]) -> String {
+    numbers
+        .iter()
+        .map(|number| format!("#{}", number))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(pr_number: i32, rollup: RollupMode) -> QueueEntry {
+        QueueEntry {
+            id: Uuid::new_v4(),
+            repository_id: 1,
+            pr_number,
+            approved_by: "alice".to_string(),
+            priority: 0,
+            status: QueueStatus::Queued,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            error_message: None,
+            batch_id: None,
+            rollup,
+            comment_id: None,
+            check_run_id: None,
+            head_sha: None,
+        }
+    }
+
+    fn candidate(rollup: RollupMode, base_branch: &str) -> Candidate {
+        Candidate {
+            entry: entry(1, rollup),
+            base_branch: base_branch.to_string(),
+            title: "Change".to_string(),
+        }
+    }
+
+    fn numbers(queue: &[QueueEntry]) -> Vec<i32> {
+        queue.iter().map(|entry| entry.pr_number).collect()
+    }
+
+    #[test]
+    fn fills_batches_with_rollup_always_first() {
+        let queue = vec![
+            entry(1, RollupMode::Maybe),
+            entry(2, RollupMode::Maybe),
+            entry(3, RollupMode::Always),
+            entry(4, RollupMode::Never),
+            entry(5, RollupMode::Always),
+        ];
+        assert_eq!(numbers(&batch_order(queue)), vec![1, 3, 5, 2, 4]);
+
+        // The head of the queue keeps its place whatever its mode
+        let queue = vec![entry(1, RollupMode::Never), entry(2, RollupMode::Always)];
+        assert_eq!(numbers(&batch_order(queue)), vec![1, 2]);
+        assert!(batch_order(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn joins_batches_on_the_same_base_branch() {
+        let first = candidate(RollupMode::Maybe, "main");
+        assert_eq!(joins(&first, RollupMode::Maybe, "main"), Some(true));
+        assert_eq!(joins(&first, RollupMode::Always, "main"), Some(true));
+        assert_eq!(joins(&first, RollupMode::Maybe, "release"), Some(false));
+        assert_eq!(joins(&first, RollupMode::Never, "main"), Some(false));
+
+        // A rollup=never PR is tested on its own
+        let first = candidate(RollupMode::Never, "main");
+        assert_eq!(joins(&first, RollupMode::Maybe, "main"), None);
+    }
+
+    #[test]
+    fn bisects_batches_in_queue_order() {
+        assert_eq!(bisect(vec![1, 2, 3, 4]), (vec![1, 2], vec![3, 4]));
+        assert_eq!(bisect(vec![1, 2, 3]), (vec![1], vec![2, 3]));
+        assert_eq!(bisect(vec![1, 2]), (vec![1], vec![2]));
+    }
+}
@@ -0,0 +1,208 @@
+// repo_config.rs
+use anyhow::Result;
+use github_merge_bot::Repository;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, sync::Arc};
+use tokio::{
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+use crate::{auth::Role, github::GitHubClient};
+
+// Read from each repository's default branch
+pub const REPO_CONFIG_PATH: &str = ".github/merge-bot.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeMethod {
+    // Fast-forward the base branch to the tested merge commit
+    Merge,
+    // Squash or rebase each PR through the pull request merge API once CI passes
+    Squash,
+    Rebase,
+}
+
+impl MergeMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MergeMethod::Merge => "merge",
+            MergeMethod::Squash => "squash",
+            MergeMethod::Rebase => "rebase",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RepoConfig {
+    pub required_checks: Vec<String>,
+    pub ci_timeout_secs: Option<u64>,
+    pub try_branch_prefix: String,
+    pub try_merge_branch_prefix: String,
+    pub merge_branch: String,
+    pub reviewers: Vec<String>,
+    pub merge_method: MergeMethod,
+    // Minimum role per command, overriding the built-in defaults
+    pub permissions: HashMap<String, Role>,
+    // Keep a PR approved when new commits leave its changes as they were, e.g. a rebase
+    pub keep_approval_on_rebase: bool,
+}
+
+impl Default for RepoConfig {
+    fn default() -> Self {
+        Self {
+            required_checks: Vec::new(),
+            ci_timeout_secs: None,
+            try_branch_prefix: "automation/bot/try".to_string(),
+            try_merge_branch_prefix: "automation/bot/try-merge".to_string(),
+            merge_branch: "automation/bot/merge".to_string(),
+            reviewers: Vec::new(),
+            merge_method: MergeMethod::Merge,
+            permissions: HashMap::new(),
+            keep_approval_on_rebase: false,
+        }
+    }
+}
+
+impl RepoConfig {
+    pub fn parse(contents: &str) -> Result<Self> {
+        let settings = ::config::Config::builder()
+            .add_source(::config::File::from_str(
+                contents,
+                ::config::FileFormat::Toml,
+            ))
+            .build()?;
+
+        Ok(settings.try_deserialize()?)
+    }
+
+    pub fn is_reviewer(&self, login: &str) -> bool {
+        self.reviewers
+            .iter()
+            .any(|reviewer| reviewer.eq_ignore_ascii_case(login))
+    }
+}
+
+// The repository's configuration file exists but could not be parsed, as opposed to
+// failing to fetch it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidConfig {
+    pub repo: String,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid {} in {}: {}",
+            REPO_CONFIG_PATH, self.repo, self.reason
+        )
+    }
+}
+
+impl std::error::Error for InvalidConfig {}
+
+// Pushes invalidate a repository's entry, the TTL catches pushes whose webhook was
+// missed or delivered to another replica
+#[derive(Debug, Clone)]
+pub struct RepoConfigCache {
+    configs: Arc<RwLock<HashMap<String, (RepoConfig, Instant)>>>,
+    ttl: Duration,
+}
+
+impl RepoConfigCache {
+    pub fn new(ttl_secs: u64) -> Self {
+        Self {
+            configs: Arc::new(RwLock::new(HashMap::new())),
+            ttl: Duration::from_secs(ttl_secs),
+        }
+    }
+
+    pub async fn get(&self, github: &GitHubClient, repo: &Repository) -> Result<RepoConfig> {
+        if let Some((config, fetched_at)) = self.configs.read().await.get(&repo.full_name) {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(config.clone());
+            }
+        }
+
+        let config = match github
+            .get_file_contents(&repo.full_name, REPO_CONFIG_PATH, &repo.default_branch)
+            .await?
+        {
+            Some(contents) => RepoConfig::parse(&contents).map_err(|e| InvalidConfig {
+                repo: repo.full_name.clone(),
+                reason: e.to_string(),
+            })?,
+            None => RepoConfig::default(),
+        };
+
+        info!("Loaded configuration for {}", repo.full_name);
+        let mut configs = self.configs.write().await;
+        configs.retain(|_, (_, fetched_at)| fetched_at.elapsed() < self.ttl);
+        configs.insert(repo.full_name.clone(), (config.clone(), Instant::now()));
+
+        Ok(config)
+    }
+
+    pub async fn invalidate(&self, repo: &str) {
+        if self.configs.write().await.remove(repo).is_some() {
+            info!("Invalidated configuration for {}", repo);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_settings_over_defaults() {
+        let config = RepoConfig::parse(
+            r#"
+            required_checks = ["test", "lint"]
+            ci_timeout_secs = 600
+            merge_method = "squash"
+            reviewers = ["Alice"]
+
+            [permissions]
+            try = "triage"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.required_checks, vec!["test", "lint"]);
+        assert_eq!(config.ci_timeout_secs, Some(600));
+        assert_eq!(config.merge_method, MergeMethod::Squash);
+        assert_eq!(config.permissions.get("try"), Some(&Role::Triage));
+        assert!(config.is_reviewer("alice"));
+        assert_eq!(config.merge_branch, RepoConfig::default().merge_branch);
+        assert!(!config.keep_approval_on_rebase);
+    }
+
+    #[test]
+    fn parses_an_empty_file_as_defaults() {
+        let config = RepoConfig::parse("").unwrap();
+        assert!(config.required_checks.is_empty());
+        assert_eq!(config.merge_method, MergeMethod::Merge);
+        assert_eq!(config.try_branch_prefix, "automation/bot/try");
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        assert!(RepoConfig::parse("required_checks = [").is_err());
+        assert!(RepoConfig::parse("merge_method = \"octopus\"").is_err());
+        assert!(RepoConfig::parse("ci_timeout_secs = \"soon\"").is_err());
+
+        let error = InvalidConfig {
+            repo: "acme/widgets".to_string(),
+            reason: "unknown variant `octopus`".to_string(),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Invalid .github/merge-bot.toml in acme/widgets: unknown variant `octopus`"
+        );
+    }
+}
@@ -0,0 +1,203 @@
+// repositories.rs
+use anyhow::Result;
+use github_merge_bot::Repository;
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{database::Database, AppState};
+
+// Full name, default branch and installation id recorded for a repository
+type Recorded = (String, String, Option<i64>);
+
+// What was last written to the `repositories` table per repository, so that the
+// webhooks of a busy repository only write when something changed
+#[derive(Debug, Clone, Default)]
+pub struct KnownRepositories {
+    recorded: Arc<RwLock<HashMap<i64, Recorded>>>,
+}
+
+impl KnownRepositories {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn record(
+        &self,
+        db: &Database,
+        repo: &Repository,
+        installation_id: Option<i64>,
+    ) -> Result<()> {
+        let key = (
+            repo.full_name.clone(),
+            repo.default_branch.clone(),
+            installation_id,
+        );
+        if self.recorded.read().await.get(&repo.id) == Some(&key) {
+            return Ok(());
+        }
+
+        self.store(db, repo, installation_id).await
+    }
+
+    // Writes the repository even if it looks unchanged, e.g. to restore one that
+    // another replica deleted
+    pub async fn store(
+        &self,
+        db: &Database,
+        repo: &Repository,
+        installation_id: Option<i64>,
+    ) -> Result<()> {
+        db.upsert_repository(repo, installation_id).await?;
+        self.recorded.write().await.insert(
+            repo.id,
+            (
+                repo.full_name.clone(),
+                repo.default_branch.clone(),
+                installation_id,
+            ),
+        );
+
+        Ok(())
+    }
+
+    async fn forget(&self, repository_ids: &[i64]) {
+        let mut recorded = self.recorded.write().await;
+        for id in repository_ids {
+            recorded.remove(id);
+        }
+    }
+}
+
+// Keeps the `repositories` table in sync with the webhooks: repositories are recorded
+// when the app is installed on them and from every payload that carries one, which also
+// picks up renames, transfers and default branch changes. Repositories the app lost
+// access to are soft-deleted.
+pub async fn sync_from_webhook(state: &AppState, event_type: &str, payload: &Value) -> Result<()> {
+    let installation_id = payload["installation"]["id"].as_i64();
+    let action = payload["action"].as_str().unwrap_or("");
+
+    match (event_type, action) {
+        ("installation", "deleted") => {
+            if let Some(installation_id) = installation_id {
+                let ids = state
+                    .db
+                    .delete_installation_repositories(installation_id)
+                    .await?;
+                state.known_repositories.forget(&ids).await;
+                info!(
+                    "Deleted {} repositories of uninstalled installation {}",
+                    ids.len(),
+                    installation_id
+                );
+            }
+            delete(state, &repository_ids(&payload["repositories"])).await?;
+        }
+        ("installation", "created" | "new_permissions_accepted" | "unsuspend") => {
+            let account = payload["installation"]["account"]["login"]
+                .as_str()
+                .unwrap_or("");
+            for repository in payload["repositories"].as_array().into_iter().flatten() {
+                record_listed(state, repository, account, installation_id).await?;
+            }
+        }
+        ("installation_repositories", "added") => {
+            let account = payload["installation"]["account"]["login"]
+                .as_str()
+                .unwrap_or("");
+            for repository in payload["repositories_added"]
+                .as_array()
+                .into_iter()
+                .flatten()
+            {
+                record_listed(state, repository, account, installation_id).await?;
+            }
+        }
+        ("installation_repositories", "removed") => {
+            delete(state, &repository_ids(&payload["repositories_removed"])).await?;
+        }
+        ("repository", "deleted") => {
+            delete(state, &repository_ids(&payload["repository"])).await?;
+        }
+        _ => {
+            if let Some(repo) = repository_from_value(&payload["repository"]) {
+                state
+                    .known_repositories
+                    .record(&state.db, &repo, installation_id)
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Installation payloads only list repository names, so the rest is fetched
+async fn record_listed(
+    state: &AppState,
+    repository: &Value,
+    account: &str,
+    installation_id: Option<i64>,
+) -> Result<()> {
+    let Some(id) = repository["id"].as_i64() else {
+        return Ok(());
+    };
+
+    let repo = match state.github.get_repository(id).await {
+        Ok(repo) => repo,
+        Err(e) => {
+            // Recorded with a guessed default branch, corrected by its next webhook
+            warn!("Could not fetch repository {}: {}", id, e);
+            Repository {
+                id,
+                name: repository["name"].as_str().unwrap_or("").to_string(),
+                full_name: repository["full_name"].as_str().unwrap_or("").to_string(),
+                owner: account.to_string(),
+                default_branch: "main".to_string(),
+            }
+        }
+    };
+
+    info!("Recording repository {}", repo.full_name);
+    state
+        .known_repositories
+        .store(&state.db, &repo, installation_id)
+        .await
+}
+
+async fn delete(state: &AppState, repository_ids: &[i64]) -> Result<()> {
+    if repository_ids.is_empty() {
+        return Ok(());
+    }
+
+    state.known_repositories.forget(repository_ids).await;
+    let deleted = state.db.delete_repositories(repository_ids).await?;
+    if deleted > 0 {
+        info!("Deleted {} repositories the app lost access to", deleted);
+    }
+
+    Ok(())
+}
+
+// Ids of a repository object or of an array of them
+fn repository_ids(value: &Value) -> Vec<i64> {
+    match value.as_array() {
+        Some(repositories) => repositories
+            .iter()
+            .filter_map(|repository| repository["id"].as_i64())
+            .collect(),
+        None => value["id"].as_i64().into_iter().collect(),
+    }
+}
+
+// The full repository object of webhook payloads; None when the payload has none
+fn repository_from_value(repository: &Value) -> Option<Repository> {
+    Some(Repository {
+        id: repository["id"].as_i64()?,
+        name: repository["name"].as_str()?.to_string(),
+        full_name: repository["full_name"].as_str()?.to_string(),
+        owner: repository["owner"]["login"].as_str()?.to_string(),
+        default_branch: repository["default_branch"].as_str()?.to_string(),
+    })
+}
@@ -0,0 +1,144 @@
+// workers.rs
+use github_merge_bot::Repository;
+use std::{collections::HashMap, sync::Arc};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::{timeout, Duration},
+};
+use tracing::{debug, error};
+
+use crate::{
+    commands::{Command, CommandError},
+    process_comment_command, process_pr_push, AppState,
+};
+
+// Workers of repositories without commands for this long shut down
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// The commands of one PR comment
+#[derive(Debug)]
+pub struct CommentCommands {
+    pub repo: Repository,
+    pub pr_number: i32,
+    pub author: String,
+    pub comment_id: i64,
+    pub commands: Vec<Result<Command, CommandError>>,
+}
+
+// New commits pushed to a PR, from `before` to `after`
+#[derive(Debug)]
+pub struct PrPush {
+    pub repo: Repository,
+    pub pr_number: i32,
+    pub base_branch: String,
+    pub pusher: String,
+    pub before: String,
+    pub after: String,
+}
+
+// Work handed to a repository's worker
+#[derive(Debug)]
+pub enum RepoTask {
+    Commands(CommentCommands),
+    Push(PrPush),
+}
+
+impl RepoTask {
+    fn repo(&self) -> &Repository {
+        match self {
+            RepoTask::Commands(comment) => &comment.repo,
+            RepoTask::Push(push) => &push.repo,
+        }
+    }
+
+    fn pr_number(&self) -> i32 {
+        match self {
+            RepoTask::Commands(comment) => comment.pr_number,
+            RepoTask::Push(push) => push.pr_number,
+        }
+    }
+}
+
+// One worker task per repository runs its commands and PR pushes one at a time and in
+// order, so work on different repositories runs in parallel. Each worker has a bounded
+// queue.
+#[derive(Debug, Clone)]
+pub struct RepoWorkers {
+    senders: Arc<Mutex<HashMap<i64, mpsc::Sender<RepoTask>>>>,
+    capacity: usize,
+}
+
+impl RepoWorkers {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            senders: Arc::new(Mutex::new(HashMap::new())),
+            capacity: capacity.max(1),
+        }
+    }
+
+    // Queues the task on the repository's worker, starting it if needed. The task is
+    // handed back when the worker's queue is full.
+    pub async fn submit(&self, state: &Arc<AppState>, task: RepoTask) -> Result<(), RepoTask> {
+        let repository_id = task.repo().id;
+        let mut senders = self.senders.lock().await;
+
+        // A worker that died on a panic is replaced
+        if senders
+            .get(&repository_id)
+            .is_some_and(|sender| sender.is_closed())
+        {
+            senders.remove(&repository_id);
+        }
+
+        let sender = senders.entry(repository_id).or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel(self.capacity);
+            debug!("Starting worker for {}", task.repo().full_name);
+            tokio::spawn(run_worker(state.clone(), repository_id, receiver));
+            sender
+        });
+
+        sender.try_send(task).map_err(|e| e.into_inner())
+    }
+}
+
+async fn run_worker(
+    state: Arc<AppState>,
+    repository_id: i64,
+    mut receiver: mpsc::Receiver<RepoTask>,
+) {
+    loop {
+        match timeout(IDLE_TIMEOUT, receiver.recv()).await {
+            Ok(Some(task)) => {
+                let (repo, pr_number) = (task.repo().clone(), task.pr_number());
+                let result = match task {
+                    RepoTask::Commands(comment) => {
+                        process_comment_command(
+                            &state,
+                            &comment.repo,
+                            comment.pr_number,
+                            &comment.author,
+                            comment.comment_id,
+                            comment.commands,
+                        )
+                        .await
+                    }
+                    RepoTask::Push(push) => process_pr_push(&state, &push).await,
+                };
+                if let Err(e) = result {
+                    error!("Error processing {}#{}: {}", repo.full_name, pr_number, e);
+                }
+            }
+            Ok(None) => return,
+            Err(_) => {
+                // Submitting happens under the same lock, so nothing can be queued
+                // between the emptiness check and the removal
+                let mut senders = state.repo_workers.senders.lock().await;
+                if receiver.is_empty() {
+                    senders.remove(&repository_id);
+                    debug!("Stopping idle worker for repository {}", repository_id);
+                    return;
+                }
+            }
+        }
+    }
+}